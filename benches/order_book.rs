@@ -0,0 +1,133 @@
+//! Throughput of the order book's hot paths.
+//!
+//! Run with `cargo bench --bench order_book`. Each case is timed over a book of
+//! `ORDERS` resting orders spread across `LEVELS` prices per side, except the hot level
+//! case, which queues `HOT_ORDERS` orders at a single price.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use market_simulation::account::{AccountId, Accounts};
+use market_simulation::market::Market;
+use market_simulation::order::{OrderBase, OrderBook, Side};
+use ordered_float::NotNan;
+
+const ORDERS: usize = 100_000;
+const LEVELS: u64 = 500;
+const HOT_ORDERS: usize = 20_000;
+
+/// xorshift so every run sees the same prices and cancel order
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn orders(account_id: AccountId, rng: &mut Rng) -> Vec<OrderBase> {
+    (0..ORDERS)
+        .map(|i| {
+            let tick = (rng.next() % LEVELS) as f64 / 100.;
+            let (side, limit) = match i % 2 {
+                0 => (Side::Bid, 99.99 - tick),
+                _ => (Side::Ask, 100. + tick),
+            };
+            OrderBase::build(limit, 1 + (rng.next() % 10) as usize, side, account_id).unwrap()
+        })
+        .collect()
+}
+
+fn report(name: &str, operations: usize, elapsed: Duration) {
+    println!(
+        "{name:<20} {operations:>8} ops {:>10.1} ms {:>12.0} ops/s",
+        elapsed.as_secs_f64() * 1e3,
+        operations as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let mut accounts = Accounts::default();
+    let account_id = accounts.create_new_account(NotNan::new(1e12).unwrap(), 0);
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+    // insert
+    let orders = orders(account_id, &mut rng);
+    let mut ids: Vec<_> = orders.iter().map(|order| order.get_id()).collect();
+    let mut order_book = OrderBook::default();
+    let start = Instant::now();
+    for order in orders {
+        order_book.insert_order(order);
+    }
+    report("insert", ORDERS, start.elapsed());
+
+    // find
+    let start = Instant::now();
+    for id in ids.iter() {
+        black_box(order_book.find_order(*id));
+    }
+    report("find", ORDERS, start.elapsed());
+
+    // depth
+    let start = Instant::now();
+    for _ in 0..100 {
+        black_box(order_book.depth(Side::Bid, Some(10)));
+    }
+    report("depth (10 levels)", 100, start.elapsed());
+
+    // cancel in a random order
+    for i in (1..ids.len()).rev() {
+        ids.swap(i, rng.next() as usize % (i + 1));
+    }
+    let start = Instant::now();
+    for id in ids {
+        black_box(order_book.delete_order(id));
+    }
+    report("cancel", ORDERS, start.elapsed());
+
+    // cancel in a random order from one busy price level
+    let hot: Vec<_> = (0..HOT_ORDERS)
+        .map(|_| OrderBase::build(100., 1, Side::Bid, account_id).unwrap())
+        .collect();
+    let mut ids: Vec<_> = hot.iter().map(|order| order.get_id()).collect();
+    for order in hot {
+        order_book.insert_order(order);
+    }
+    for i in (1..ids.len()).rev() {
+        ids.swap(i, rng.next() as usize % (i + 1));
+    }
+    let start = Instant::now();
+    for id in ids {
+        black_box(order_book.delete_order(id));
+    }
+    report("cancel (hot level)", HOT_ORDERS, start.elapsed());
+
+    // match market orders against a full book
+    let mut market = Market::default();
+    let maker_id = market.new_account(1e12, 1_000_000_000).unwrap();
+    let taker_id = market.new_account(1e12, 1_000_000_000).unwrap();
+    for order in self::orders(maker_id, &mut rng) {
+        market.handle_incoming_order(order).unwrap();
+    }
+    let takers: Vec<_> = (0..ORDERS / 10)
+        .map(|i| {
+            let side = if i % 2 == 0 { Side::Bid } else { Side::Ask };
+            OrderBase::build_market(5, side, taker_id).unwrap()
+        })
+        .collect();
+    let start = Instant::now();
+    for order in takers {
+        black_box(market.handle_incoming_order(order).unwrap());
+    }
+    report("match", ORDERS / 10, start.elapsed());
+
+    // orders of a single account
+    let start = Instant::now();
+    for _ in 0..100 {
+        black_box(market.get_orders_by_account(taker_id).count());
+    }
+    report("orders by account", 100, start.elapsed());
+}
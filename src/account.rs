@@ -1,10 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use ordered_float::NotNan;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use super::order::{OrderBase, Side};
+use super::fees::{FeeSchedule, Liquidity, VOLUME_WINDOW};
+use super::order::Side;
 
 #[derive(Debug, Eq, Hash, PartialEq, Copy, Clone)]
 pub struct AccountId {
@@ -22,84 +23,425 @@ impl AccountId {
     }
 }
 
+/// How much equity an account must hold against its exposure, as fractions of the
+/// exposure valued at the mark price
+#[derive(Debug, Copy, Clone)]
+pub struct Margin {
+    /// Required to take on more exposure. 1 allows no leverage, 0.5 allows 2x.
+    pub initial: f64,
+    /// Below this an account is short of margin
+    pub maintenance: f64,
+    /// Leverage limit for accounts that haven't been given their own
+    pub max_leverage: Option<f64>,
+}
+
+impl Default for Margin {
+    fn default() -> Margin {
+        Margin {
+            initial: 1.,
+            maintenance: 0.5,
+            max_leverage: None,
+        }
+    }
+}
+
+/// An account closed out for falling below its maintenance margin
+#[derive(Debug, Clone)]
+pub struct Liquidation {
+    pub timestamp: f64,
+    pub mark_price: NotNan<f64>,
+    pub equity: f64,
+    pub maintenance_margin: f64,
+    /// The position being closed
+    pub position: i32,
+    pub cancelled_orders: Vec<Uuid>,
+    /// The market order sent to close the position
+    pub order_id: Uuid,
+}
+
+impl Liquidation {
+    pub fn view(&self) -> LiquidationView {
+        LiquidationView {
+            timestamp: self.timestamp,
+            mark_price: self.mark_price.into_inner(),
+            equity: self.equity,
+            maintenance_margin: self.maintenance_margin,
+            position: self.position,
+            cancelled_orders: self
+                .cancelled_orders
+                .iter()
+                .map(|order_id| order_id.to_string())
+                .collect(),
+            order_id: self.order_id.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LiquidationView {
+    pub timestamp: f64,
+    pub mark_price: f64,
+    pub equity: f64,
+    pub maintenance_margin: f64,
+    pub position: i32,
+    pub cancelled_orders: Vec<String>,
+    pub order_id: String,
+}
+
+/// Which open lots a fill that reduces a position is matched against to realize P&L
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CostBasis {
+    /// The oldest lots first
+    #[default]
+    Fifo,
+    /// The whole position at its average entry price
+    AverageCost,
+}
+
+// part of a position opened at one price, negative when short
+#[derive(Debug)]
+struct Lot {
+    quantity: i32,
+    price: f64,
+}
+
+// quantity and value of an account's live orders on one side
+#[derive(Debug, Default)]
+struct OpenOrders {
+    quantity: i32,
+    notional: NotNan<f64>,
+}
+
 #[derive(Debug)]
 pub struct Account {
     id: Uuid,
+    // how many accounts were created before this one
+    created: usize,
     account_balance: NotNan<f64>,
     position: i32,
+    max_leverage: Option<NotNan<f64>>,
+    // held back for live orders, see `Accounts::reserve`
+    bids: OpenOrders,
+    asks: OpenOrders,
+    liquidations: Vec<Liquidation>,
+    // what is left of the position the account was created with, which has no entry price
+    unpriced: i32,
+    // the rest of the open position by entry price, oldest first, see `Account::record_fill`
+    lots: VecDeque<Lot>,
+    realized_pnl: f64,
+    trade_count: usize,
+    fees_paid: f64,
+    // (timestamp, notional) of each fill within the fee volume window, oldest first
+    fills: VecDeque<(f64, f64)>,
+    volume: f64,
 }
 
 impl Account {
-    fn new(account_balance: NotNan<f64>, position: i32) -> Account {
+    fn new(account_balance: NotNan<f64>, position: i32, created: usize) -> Account {
         Account {
             id: Uuid::new_v4(),
+            created,
             account_balance,
             position,
+            max_leverage: None,
+            bids: OpenOrders::default(),
+            asks: OpenOrders::default(),
+            liquidations: Vec::new(),
+            unpriced: position,
+            lots: VecDeque::new(),
+            realized_pnl: 0.,
+            trade_count: 0,
+            fees_paid: 0.,
+            fills: VecDeque::new(),
+            volume: 0.,
         }
     }
     pub fn get_id(&self) -> Uuid {
         self.id
     }
-    pub fn view(&self) -> AccountView {
-        AccountView {
-            id: self.id.to_string(),
-            account_balance: self.account_balance.into_inner(),
-            position: self.position,
+    /// Cash not held back for live bids
+    pub fn available_balance(&self) -> NotNan<f64> {
+        self.account_balance - self.bids.notional
+    }
+    /// Position not held back for live asks
+    pub fn available_position(&self) -> i32 {
+        self.position - self.asks.quantity
+    }
+    /// Cash plus the position valued at `mark`
+    pub fn equity(&self, mark: NotNan<f64>) -> f64 {
+        (self.account_balance + mark * f64::from(self.position)).into_inner()
+    }
+    /// Average price the open position was entered at, leaving out the unpriced part
+    pub fn average_entry_price(&self) -> Option<f64> {
+        let quantity: i32 = self.lots.iter().map(|lot| lot.quantity.abs()).sum();
+        let cost: f64 = self
+            .lots
+            .iter()
+            .map(|lot| f64::from(lot.quantity.abs()) * lot.price)
+            .sum();
+        (quantity > 0).then(|| cost / f64::from(quantity))
+    }
+    /// What closing the open position at `mark` would realize
+    pub fn unrealized_pnl(&self, mark: NotNan<f64>) -> f64 {
+        self.lots
+            .iter()
+            .map(|lot| f64::from(lot.quantity) * (mark.into_inner() - lot.price))
+            .sum()
+    }
+    // Charges the fee for a fill at `timestamp`, which must not be before earlier fills',
+    // and counts it towards the account's volume
+    fn charge(
+        &mut self,
+        fees: &FeeSchedule,
+        liquidity: Liquidity,
+        price: f64,
+        quantity: usize,
+        timestamp: f64,
+    ) -> f64 {
+        while let Some((_, notional)) = self
+            .fills
+            .front()
+            .filter(|(filled_at, _)| *filled_at <= timestamp - VOLUME_WINDOW)
+        {
+            self.volume -= notional;
+            self.fills.pop_front();
+        }
+        let fee = fees.fee(self.volume, liquidity, price, quantity);
+        self.account_balance -= fee;
+        self.fees_paid += fee;
+
+        let notional = price * quantity as f64;
+        self.fills.push_back((timestamp, notional));
+        self.volume += notional;
+        fee
+    }
+    // Updates the lots and realized P&L for a fill of `quantity`, negative when selling, at
+    // `price`. A fill against the position closes what is left of the position the account
+    // was created with first, which realizes nothing as it has no entry price.
+    fn record_fill(&mut self, quantity: i32, price: f64, cost_basis: CostBasis) {
+        self.trade_count += 1;
+
+        let mut remaining = quantity;
+        if self.unpriced.signum() == -remaining.signum() {
+            let closed = self.unpriced.abs().min(remaining.abs()) * self.unpriced.signum();
+            self.unpriced -= closed;
+            remaining += closed;
+        }
+        while remaining != 0 {
+            match self.lots.front_mut() {
+                // a fill against the position closes its lots, oldest first
+                Some(lot) if lot.quantity.signum() != remaining.signum() => {
+                    let closed = lot.quantity.abs().min(remaining.abs()) * lot.quantity.signum();
+                    self.realized_pnl += f64::from(closed) * (price - lot.price);
+                    lot.quantity -= closed;
+                    remaining += closed;
+                    if lot.quantity == 0 {
+                        self.lots.pop_front();
+                    }
+                }
+                _ => break,
+            }
+        }
+        if remaining == 0 {
+            return;
+        }
+        match (cost_basis, self.lots.back_mut()) {
+            (CostBasis::AverageCost, Some(lot)) => {
+                let quantity = lot.quantity + remaining;
+                lot.price = (f64::from(lot.quantity) * lot.price + f64::from(remaining) * price)
+                    / f64::from(quantity);
+                lot.quantity = quantity;
+            }
+            _ => self.lots.push_back(Lot {
+                quantity: remaining,
+                price,
+            }),
         }
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AccountView {
     pub id: String,
     pub account_balance: f64,
     pub position: i32,
+    pub reserved_balance: f64,
+    pub available_balance: f64,
+    pub reserved_position: i32,
+    pub available_position: i32,
+    /// Balance plus position at the mark price. This, the margins and unrealized P&L are
+    /// missing until the market has traded.
+    pub equity: Option<f64>,
+    /// Equity the position requires before more exposure can be taken on
+    pub initial_margin: Option<f64>,
+    /// Equity the position requires to stay open
+    pub maintenance_margin: Option<f64>,
+    pub max_leverage: Option<f64>,
+    /// What is left of the position the account was created with. It has no entry price,
+    /// so the average entry price and P&L leave it out.
+    pub unpriced_position: i32,
+    pub average_entry_price: Option<f64>,
+    pub realized_pnl: f64,
+    pub unrealized_pnl: Option<f64>,
+    /// Fills the account has been party to
+    pub trade_count: usize,
+    /// Net of rebates. Realized P&L does not include them.
+    pub fees_paid: f64,
 }
 
 #[derive(Debug, Default)]
 pub struct Accounts {
     accounts: HashMap<Uuid, Account>,
+    margin: Margin,
+    cost_basis: CostBasis,
+    fees: FeeSchedule,
 }
 
 impl Accounts {
+    pub fn with_margin(margin: Margin) -> Accounts {
+        Accounts {
+            margin,
+            ..Default::default()
+        }
+    }
+    pub fn with_cost_basis(mut self, cost_basis: CostBasis) -> Accounts {
+        self.cost_basis = cost_basis;
+        self
+    }
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Accounts {
+        self.fees = fees;
+        self
+    }
+    /// Whether the account's equity would still cover its initial margin if every live order
+    /// on `side` filled, along with another `quantity` at `price`, all of it paying the taker
+    /// fee. Positions are valued at `mark`, or at `price` before the market has traded.
+    /// Orders that only shrink the position are always allowed.
     pub fn check_sufficient_balance(
         &self,
         account_id: AccountId,
-        order: &OrderBase
+        side: Side,
+        price: NotNan<f64>,
+        quantity: usize,
+        mark: Option<NotNan<f64>>,
     ) -> bool {
         let account = self.get(&account_id);
-        match order.side {
-            Side::Bid => {
-                let requirement = order.limit * order.quantity as f64;
-                if account.account_balance < requirement {
-                    return false;
-                }
-            }
-            Side::Ask => {
-                let difference = account.position - order.quantity as i32;
-                if difference < 0 {
-                    let requirement = order.limit * difference as f64 * 0.5;
-                    
-                    if account.account_balance < requirement {
-                        return false;
-                    }
-                } 
-            }
+        let open = match side {
+            Side::Bid => &account.bids,
+            Side::Ask => &account.asks,
+        };
+        let sign = side as i32;
+        let position = account.position + sign * (open.quantity + quantity as i32);
+        if position.abs() <= account.position.abs() {
+            return true;
         }
-        true
+        let filled = open.quantity + quantity as i32;
+        let notional = open.notional + price * quantity as f64;
+        // a taker rebate is not counted on, as the orders may end up resting instead
+        let fee = self
+            .fees
+            .fee(
+                account.volume,
+                Liquidity::Taker,
+                notional.into_inner() / f64::from(filled),
+                filled as usize,
+            )
+            .max(0.);
+        let balance = account.account_balance - notional * f64::from(sign) - fee;
+        let mark = mark.unwrap_or(price);
+        let equity = balance + mark * f64::from(position);
+        let exposure = mark * f64::from(position.abs());
+        equity >= exposure * self.initial_ratio(account)
+    }
+    /// Whether the account's equity has fallen below its maintenance margin at `mark`.
+    /// An account without a position has nothing left to close, so it never is.
+    pub fn is_under_margin(&self, account_id: AccountId, mark: NotNan<f64>) -> bool {
+        let account = self.get(&account_id);
+        account.position != 0 && account.equity(mark) < self.maintenance_margin(account, mark)
+    }
+    /// Every account under its maintenance margin at `mark`, see [`Accounts::is_under_margin`]
+    pub fn under_margin(&self, mark: NotNan<f64>) -> Vec<AccountId> {
+        let mut accounts: Vec<&Account> = self
+            .accounts
+            .values()
+            .filter(|account| self.is_under_margin(AccountId::new(account), mark))
+            .collect();
+        accounts.sort_by_key(|account| account.created);
+        accounts.into_iter().map(AccountId::new).collect()
+    }
+    pub fn record_liquidation(&mut self, account_id: AccountId, liquidation: Liquidation) {
+        self.get_mut(&account_id).liquidations.push(liquidation);
+    }
+    /// The account's liquidations, oldest first
+    pub fn liquidations(&self, account_id: &AccountId) -> &[Liquidation] {
+        &self.get(account_id).liquidations
+    }
+    /// Sets the most exposure the account may take on for its equity, or falls back to the
+    /// market's limit when `None`
+    pub fn set_max_leverage(
+        &mut self,
+        account_id: AccountId,
+        max_leverage: Option<f64>,
+    ) -> Result<(), &'static str> {
+        let max_leverage = max_leverage
+            .map(NotNan::new)
+            .transpose()
+            .ok()
+            .filter(|leverage| leverage.is_none_or(|leverage| leverage.into_inner() > 0.))
+            .ok_or("Leverage limit must be positive")?;
+        self.get_mut(&account_id).max_leverage = max_leverage;
+        Ok(())
+    }
+    pub fn view(&self, account_id: &AccountId, mark: Option<NotNan<f64>>) -> AccountView {
+        let account = self.get(account_id);
+        AccountView {
+            id: account.id.to_string(),
+            account_balance: account.account_balance.into_inner(),
+            position: account.position,
+            reserved_balance: account.bids.notional.into_inner(),
+            available_balance: account.available_balance().into_inner(),
+            reserved_position: account.asks.quantity,
+            available_position: account.available_position(),
+            equity: mark.map(|mark| account.equity(mark)),
+            initial_margin: mark.map(|mark| {
+                mark.into_inner() * f64::from(account.position.abs()) * self.initial_ratio(account)
+            }),
+            maintenance_margin: mark.map(|mark| self.maintenance_margin(account, mark)),
+            max_leverage: self.max_leverage(account).map(NotNan::into_inner),
+            unpriced_position: account.unpriced,
+            average_entry_price: account.average_entry_price(),
+            realized_pnl: account.realized_pnl,
+            unrealized_pnl: mark.map(|mark| account.unrealized_pnl(mark)),
+            trade_count: account.trade_count,
+            fees_paid: account.fees_paid,
+        }
+    }
+    fn max_leverage(&self, account: &Account) -> Option<NotNan<f64>> {
+        account.max_leverage.or_else(|| {
+            self.margin
+                .max_leverage
+                .and_then(|leverage| NotNan::new(leverage).ok())
+        })
+    }
+    // a leverage limit tighter than the initial margin takes its place
+    fn initial_ratio(&self, account: &Account) -> f64 {
+        match self.max_leverage(account) {
+            Some(leverage) => self.margin.initial.max(leverage.recip()),
+            None => self.margin.initial,
+        }
+    }
+    fn maintenance_margin(&self, account: &Account, mark: NotNan<f64>) -> f64 {
+        mark.into_inner() * f64::from(account.position.abs()) * self.margin.maintenance
     }
     pub fn create_new_account(&mut self, account_balance: NotNan<f64>, position: i32) -> AccountId {
-        let account = Account::new(account_balance, position);
-        let accountId = AccountId::new(&account);
-        self.accounts.insert(accountId.as_uuid(), account);
+        let account = Account::new(account_balance, position, self.accounts.len());
+        let account_id = AccountId::new(&account);
+        self.accounts.insert(account_id.as_uuid(), account);
 
-        accountId
+        account_id
     }
     pub fn check_uuid(&self, uuid: Uuid) -> Option<AccountId> {
-        let Some(account) = self.accounts.get(&uuid) else {
-            return None;
-        };
-        Some(AccountId::new(&account))
+        let account = self.accounts.get(&uuid)?;
+        Some(AccountId::new(account))
     }
     pub fn get(&self, account_id: &AccountId) -> &Account {
         self.accounts.get(&account_id.as_uuid()).expect(
@@ -113,6 +455,45 @@ impl Accounts {
         // realisation: the /account DELETE route can refuse unless the client has deleted all existing orders. So it pushes some of the responsiblity on the client
         // The destructor needs to also delete any AccountId associated. Wow this is complicated
     }
+    /// Holds back what a live order for `quantity` at `price` could use if it fills:
+    /// cash for a bid and position for an ask. Both count towards the account's margin.
+    pub fn reserve(
+        &mut self,
+        account_id: AccountId,
+        side: Side,
+        price: NotNan<f64>,
+        quantity: usize,
+    ) {
+        let open = match side {
+            Side::Bid => &mut self.get_mut(&account_id).bids,
+            Side::Ask => &mut self.get_mut(&account_id).asks,
+        };
+        open.quantity += quantity as i32;
+        open.notional += price * quantity as f64;
+    }
+    /// Undoes [`Accounts::reserve`] once the order is no longer live
+    pub fn release(
+        &mut self,
+        account_id: AccountId,
+        side: Side,
+        price: NotNan<f64>,
+        quantity: usize,
+    ) {
+        let open = match side {
+            Side::Bid => &mut self.get_mut(&account_id).bids,
+            Side::Ask => &mut self.get_mut(&account_id).asks,
+        };
+        open.quantity -= quantity as i32;
+        open.notional -= price * quantity as f64;
+    }
+    fn get_mut(&mut self, account_id: &AccountId) -> &mut Account {
+        self.accounts
+            .get_mut(&account_id.as_uuid())
+            .expect("every AccountId has an Account, see Accounts::get")
+    }
+    /// Settles a trade of `quantity` at `limit` taken by `aggressor_id` on `side`, charging
+    /// the aggressor taker fees and the counterparty maker fees. Returns the fees as
+    /// (aggressor, counterparty).
     pub fn handle_transaction(
         &mut self,
         aggressor_id: AccountId,
@@ -120,14 +501,23 @@ impl Accounts {
         side: Side,
         limit: f64,
         quantity: usize,
-    ) {
+        timestamp: f64,
+    ) -> (f64, f64) {
         let aggressor = &mut self.accounts.get_mut(&aggressor_id.as_uuid()).unwrap();
         aggressor.position += (quantity as i32) * (side as i32);
         aggressor.account_balance -= (quantity as f64) * limit * (side as i32) as f64;
+        aggressor.record_fill((quantity as i32) * (side as i32), limit, self.cost_basis);
+        let aggressor_fee =
+            aggressor.charge(&self.fees, Liquidity::Taker, limit, quantity, timestamp);
 
         let counterparty = &mut self.accounts.get_mut(&counterparty_id.as_uuid()).unwrap();
         counterparty.position -= (quantity as i32) * (side as i32);
         counterparty.account_balance += (quantity as f64) * limit * (side as i32) as f64;
+        counterparty.record_fill(-(quantity as i32) * (side as i32), limit, self.cost_basis);
+        let counterparty_fee =
+            counterparty.charge(&self.fees, Liquidity::Maker, limit, quantity, timestamp);
+
+        (aggressor_fee, counterparty_fee)
     }
 }
 
@@ -148,4 +538,155 @@ mod tests {
         assert_eq!(account1_id.as_uuid(), accounts.get(&account1_id).id);
         assert_eq!(account2_id.as_uuid(), accounts.get(&account2_id).id);
     }
+    #[test]
+    fn accounts_reserve() {
+        let mut accounts = Accounts::default();
+        let account_id = accounts.create_new_account(NotNan::new(1000.).unwrap(), 10);
+        let price = NotNan::new(10.).unwrap();
+
+        accounts.reserve(account_id, Side::Bid, price, 60);
+        accounts.reserve(account_id, Side::Ask, price, 4);
+        let view = accounts.view(&account_id, None);
+        assert_eq!(view.reserved_balance, 600.);
+        assert_eq!(view.available_balance, 400.);
+        assert_eq!(view.reserved_position, 4);
+        assert_eq!(view.available_position, 6);
+
+        // the reserved cash can't be spent again
+        assert!(!accounts.check_sufficient_balance(account_id, Side::Bid, price, 60, None));
+        assert!(accounts.check_sufficient_balance(account_id, Side::Bid, price, 40, None));
+
+        accounts.release(account_id, Side::Bid, price, 60);
+        accounts.release(account_id, Side::Ask, price, 4);
+        let view = accounts.view(&account_id, None);
+        assert_eq!(view.available_balance, 1000.);
+        assert_eq!(view.available_position, 10);
+    }
+    #[test]
+    fn accounts_margin() {
+        let mut accounts = Accounts::with_margin(Margin {
+            initial: 0.5,
+            maintenance: 0.25,
+            max_leverage: None,
+        });
+        let alice_id = accounts.create_new_account(NotNan::new(1000.).unwrap(), 0);
+        let bob_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 1000);
+        let price = NotNan::new(10.).unwrap();
+
+        // short sales need the same margin as purchases
+        assert!(accounts.check_sufficient_balance(alice_id, Side::Ask, price, 200, None));
+        assert!(!accounts.check_sufficient_balance(alice_id, Side::Ask, price, 201, None));
+        // a tighter leverage limit replaces the initial margin
+        accounts.set_max_leverage(alice_id, Some(1.)).unwrap();
+        assert!(!accounts.check_sufficient_balance(alice_id, Side::Bid, price, 101, None));
+        assert!(accounts.set_max_leverage(alice_id, Some(0.)).is_err());
+        accounts.set_max_leverage(alice_id, None).unwrap();
+
+        accounts.handle_transaction(alice_id, bob_id, Side::Bid, 10., 200, 0.);
+        let view = accounts.view(&alice_id, Some(price));
+        assert_eq!(view.equity, Some(1000.));
+        assert_eq!(view.initial_margin, Some(1000.));
+        assert_eq!(view.maintenance_margin, Some(500.));
+        assert!(!accounts.is_under_margin(alice_id, price));
+        assert!(accounts.is_under_margin(alice_id, NotNan::new(6.).unwrap()));
+        // accounts are liquidated in the order they were created
+        let charlie_id = accounts.create_new_account(NotNan::new(1000.).unwrap(), 0);
+        let dan_id = accounts.create_new_account(NotNan::new(1000.).unwrap(), 0);
+        accounts.handle_transaction(charlie_id, bob_id, Side::Bid, 10., 200, 0.);
+        accounts.handle_transaction(dan_id, bob_id, Side::Bid, 10., 200, 0.);
+        assert_eq!(
+            accounts.under_margin(NotNan::new(6.).unwrap()),
+            vec![alice_id, charlie_id, dan_id]
+        );
+        // selling out of the position needs no margin however far under it is
+        assert!(accounts.check_sufficient_balance(
+            alice_id,
+            Side::Ask,
+            NotNan::new(6.).unwrap(),
+            200,
+            Some(NotNan::new(6.).unwrap())
+        ));
+    }
+    #[test]
+    fn accounts_profit_and_loss() {
+        for (cost_basis, realized, entry) in [
+            (CostBasis::Fifo, [250., 275.], [20., 25.]),
+            (CostBasis::AverageCost, [225., 275.], [15., 25.]),
+        ] {
+            let mut accounts = Accounts::default().with_cost_basis(cost_basis);
+            let alice_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 0);
+            let bob_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 1000);
+
+            accounts.handle_transaction(alice_id, bob_id, Side::Bid, 10., 10, 0.);
+            accounts.handle_transaction(alice_id, bob_id, Side::Bid, 20., 10, 0.);
+            accounts.handle_transaction(alice_id, bob_id, Side::Ask, 30., 15, 0.);
+            let view = accounts.view(&alice_id, Some(NotNan::new(30.).unwrap()));
+            assert_eq!(view.realized_pnl, realized[0]);
+            assert_eq!(view.average_entry_price, Some(entry[0]));
+            assert_eq!(view.unrealized_pnl, Some(5. * (30. - entry[0])));
+            assert_eq!(view.trade_count, 3);
+
+            // selling through the position closes it and opens a short
+            accounts.handle_transaction(alice_id, bob_id, Side::Ask, 25., 10, 0.);
+            let view = accounts.view(&alice_id, Some(NotNan::new(30.).unwrap()));
+            assert_eq!(view.position, -5);
+            assert_eq!(view.realized_pnl, realized[1]);
+            assert_eq!(view.average_entry_price, Some(entry[1]));
+            assert_eq!(view.unrealized_pnl, Some(-25.));
+
+            // bob's opening position has no entry price, so selling out of it realizes nothing
+            let view = accounts.view(&bob_id, Some(NotNan::new(30.).unwrap()));
+            assert_eq!(view.position, 1005);
+            assert_eq!(view.unpriced_position, 980);
+            assert_eq!(view.realized_pnl, 0.);
+            assert_eq!(view.average_entry_price, Some(28.));
+            assert_eq!(view.unrealized_pnl, Some(50.));
+            assert_eq!(view.trade_count, 4);
+        }
+    }
+    #[test]
+    fn accounts_fees() {
+        use crate::fees::{Rate, Tier};
+
+        let mut accounts = Accounts::default().with_fees(FeeSchedule::tiered(vec![
+            Tier {
+                volume: 0.,
+                maker: Rate::BasisPoints(-1.),
+                taker: Rate::BasisPoints(5.),
+            },
+            Tier {
+                volume: 1000.,
+                maker: Rate::BasisPoints(-2.),
+                taker: Rate::BasisPoints(2.),
+            },
+        ]));
+        let alice_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 1000);
+
+        // alice takes, bob makes
+        let fees = accounts.handle_transaction(alice_id, bob_id, Side::Bid, 10., 100, 0.);
+        assert_eq!(fees, (0.5, -0.1));
+        // both have now traded 1000 of notional
+        let fees = accounts.handle_transaction(alice_id, bob_id, Side::Bid, 10., 100, 1.);
+        assert_eq!(fees, (0.2, -0.2));
+        // the first trade has left the window but the second still counts
+        let fees =
+            accounts.handle_transaction(alice_id, bob_id, Side::Bid, 10., 100, VOLUME_WINDOW);
+        assert_eq!(fees, (0.2, -0.2));
+        // and then neither does
+        let fees =
+            accounts.handle_transaction(alice_id, bob_id, Side::Bid, 10., 100, 3. * VOLUME_WINDOW);
+        assert_eq!(fees, (0.5, -0.1));
+
+        let alice = accounts.view(&alice_id, None);
+        assert_eq!(alice.fees_paid, 1.4);
+        assert_eq!(alice.account_balance, 1e5 - 4000. - 1.4);
+        assert_eq!(accounts.view(&bob_id, None).fees_paid, -0.6);
+
+        // the taker fee has to be covered along with the order
+        let charlie_id = accounts.create_new_account(NotNan::new(1000.).unwrap(), 0);
+        let price = NotNan::new(10.).unwrap();
+        assert!(!accounts.check_sufficient_balance(charlie_id, Side::Bid, price, 100, None));
+        assert!(accounts.check_sufficient_balance(charlie_id, Side::Bid, price, 99, None));
+    }
 }
@@ -1,21 +1,25 @@
-use std::sync::Arc;
-
 use axum::{
     debug_handler,
-    extract::{Json, Path, Query, State},
+    extract::{
+        ws::{WebSocket, WebSocketUpgrade},
+        Json, State,
+    },
     http::{header::HeaderMap, StatusCode},
     response::{IntoResponse, Response, Result},
 };
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 
-use super::{parse_account_id_from_header, AppError, MarketStateHandle};
-use market_simulation::{account::AccountId, market};
+use super::{forward_updates, parse_account_id_from_header, AppError, MarketStateHandle};
+use market_simulation::account::{AccountId, LiquidationView};
+use market_simulation::feed::{AccountEvent, AccountUpdate};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AccountReqBody {
     account_balance: f64,
     position: i32,
+    /// Overrides the market's leverage limit for this account
+    #[serde(default)]
+    max_leverage: Option<f64>,
 }
 
 #[debug_handler]
@@ -23,10 +27,19 @@ pub async fn new_account(
     State(market): State<MarketStateHandle>,
     Json(account_req_body): Json<AccountReqBody>,
 ) -> impl IntoResponse {
-    let Ok(account_id) = market
-        .lock()
-        .await
-        .new_account(account_req_body.account_balance, account_req_body.position)
+    if account_req_body
+        .max_leverage
+        .is_some_and(|leverage| leverage.is_nan() || leverage <= 0.)
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            "field `max_leverage` in Body is invalid",
+        )
+            .into_response();
+    }
+    let mut market = market.lock().await;
+    let Ok(account_id) =
+        market.new_account(account_req_body.account_balance, account_req_body.position)
     else {
         return (
             StatusCode::BAD_REQUEST,
@@ -34,6 +47,9 @@ pub async fn new_account(
         )
             .into_response();
     };
+    market
+        .set_max_leverage(account_id, account_req_body.max_leverage)
+        .expect("leverage limit was checked above");
     account_id.as_uuid().to_string().into_response()
 }
 
@@ -48,6 +64,56 @@ pub async fn get_account(
         .check_account_uuid(account_id)
         .ok_or(AppError::AccountDoesNotExist)?;
 
-    let account = market.get_account(&account_id);
-    Ok(Json(account.view()).into_response())
+    Ok(Json(market.account_view(&account_id)).into_response())
+}
+
+/// Every time the account was closed out for falling below its maintenance margin, oldest first
+pub async fn get_liquidations(
+    State(market): State<MarketStateHandle>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let account_id = parse_account_id_from_header(headers)?;
+
+    let market = market.lock().await;
+    let account_id = market
+        .check_account_uuid(account_id)
+        .ok_or(AppError::AccountDoesNotExist)?;
+
+    let liquidations: Vec<LiquidationView> = market
+        .liquidations(&account_id)
+        .iter()
+        .map(|liquidation| liquidation.view())
+        .collect();
+    Ok(Json(liquidations).into_response())
+}
+
+/// Streams the account's order acknowledgements, fills, cancels, rejects and balance changes
+/// as JSON, starting with the account as it stands
+pub async fn stream(
+    headers: HeaderMap,
+    State(market): State<MarketStateHandle>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, AppError> {
+    let account_id = parse_account_id_from_header(headers)?;
+    let account_id = market
+        .lock()
+        .await
+        .check_account_uuid(account_id)
+        .ok_or(AppError::AccountDoesNotExist)?;
+
+    Ok(ws.on_upgrade(move |socket| stream_updates(socket, market, account_id)))
+}
+
+async fn stream_updates(socket: WebSocket, market: MarketStateHandle, account_id: AccountId) {
+    let (updates, snapshot) = {
+        let mut market = market.lock().await;
+        let snapshot = AccountUpdate {
+            sequence: market.account_feed_sequence(account_id),
+            event: AccountEvent::Account {
+                account: market.account_view(&account_id),
+            },
+        };
+        (market.subscribe_account(account_id), snapshot)
+    };
+    forward_updates(socket, snapshot, updates).await;
 }
@@ -1,14 +1,126 @@
 use axum::{
-    debug_handler,
-    extract::{Path, Query, State},
+    extract::{
+        ws::{WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::header::HeaderMap,
     response::{IntoResponse, Response},
     Json
 };
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use super::{AppError, MarketStateHandle};
+use super::{forward_updates, parse_account_id_from_header, AppError, MarketStateHandle};
+use market_simulation::bars::{Bar, Interval};
+use market_simulation::feed::{MarketEvent, MarketUpdate};
+use market_simulation::order::{BookEntryView, PriceLevel};
 
-pub async fn bars() -> Result<Response, AppError> {
-    todo!()
+const DEFAULT_BARS: usize = 100;
+const MAX_BARS: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BarsQuery {
+    #[serde(default)]
+    interval: Interval,
+    from: Option<f64>,
+    to: Option<f64>,
+    limit: Option<usize>,
+}
+
+pub async fn bars(
+    State(market): State<MarketStateHandle>,
+    Query(bars_query): Query<BarsQuery>,
+) -> Result<Response, AppError> {
+    let limit = bars_query.limit.unwrap_or(DEFAULT_BARS).min(MAX_BARS);
+
+    let market = market.lock().await;
+    let bars: Vec<Bar> = market.bars(bars_query.interval, bars_query.from, bars_query.to, limit);
+
+    Ok(Json(bars).into_response())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DepthQuery {
+    levels: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DepthView {
+    asks: Vec<PriceLevel>,
+    bids: Vec<PriceLevel>,
+}
+
+pub async fn depth(
+    State(market): State<MarketStateHandle>,
+    Query(depth_query): Query<DepthQuery>,
+) -> Result<Response, AppError> {
+    let market = market.lock().await;
+    let (asks, bids) = market.depth(depth_query.levels);
+
+    Ok(Json(DepthView { asks, bids }).into_response())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BookView {
+    asks: Vec<BookEntryView>,
+    bids: Vec<BookEntryView>,
+}
+
+pub async fn book(
+    headers: HeaderMap,
+    State(market): State<MarketStateHandle>,
+) -> Result<Response, AppError> {
+    // the `account-id` header is optional here and only reveals the requester's own orders
+    let account_id = if headers.contains_key("account-id") {
+        Some(parse_account_id_from_header(headers)?)
+    } else {
+        None
+    };
+
+    let market = market.lock().await;
+    let account_id = account_id
+        .map(|account_id| {
+            market
+                .check_account_uuid(account_id)
+                .ok_or(AppError::AccountDoesNotExist)
+        })
+        .transpose()?;
+
+    let (asks, bids) = market.book(account_id);
+
+    Ok(Json(BookView { asks, bids }).into_response())
+}
+
+const DEFAULT_TRADES: usize = 100;
+const MAX_TRADES: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TradesQuery {
+    limit: Option<usize>,
+    /// id of the last trade already seen
+    since: Option<String>,
+}
+
+pub async fn trades(
+    State(market): State<MarketStateHandle>,
+    Query(trades_query): Query<TradesQuery>,
+) -> Result<Response, AppError> {
+    let since = trades_query
+        .since
+        .map(|since| Uuid::try_parse(&since).map_err(|_| AppError::TradeDoesNotExist))
+        .transpose()?;
+    let limit = trades_query.limit.unwrap_or(DEFAULT_TRADES).min(MAX_TRADES);
+
+    let market = market.lock().await;
+    let trades = market.trades_since(since, limit)?;
+
+    Ok(Json(
+        trades
+            .iter()
+            .map(|trade| trade.public_view())
+            .collect::<Vec<_>>(),
+    )
+    .into_response())
 }
 
 pub async fn quote(
@@ -18,14 +130,28 @@ pub async fn quote(
     let market = market.lock().await;
     let (ask, bid) = market.quote();
 
-    let ask = match ask {
-        Some(ask) => Some(ask.view()),
-        None => None
-    }; 
-    let bid = match bid {
-        Some(bid) => Some(bid.view()),
-        None => None
-    }; 
+    let ask = ask.map(|ask| ask.public_view());
+    let bid = bid.map(|bid| bid.public_view());
 
     Json((ask, bid)).into_response()
 }
+
+/// Streams top of book changes, depth changes and trades as JSON, starting with a snapshot
+/// of the book
+pub async fn stream(ws: WebSocketUpgrade, State(market): State<MarketStateHandle>) -> Response {
+    ws.on_upgrade(move |socket| stream_updates(socket, market))
+}
+
+async fn stream_updates(socket: WebSocket, market: MarketStateHandle) {
+    // subscribing under the same lock as the snapshot means no update falls between them
+    let (updates, snapshot) = {
+        let market = market.lock().await;
+        let (asks, bids) = market.depth(None);
+        let snapshot = MarketUpdate {
+            sequence: market.feed_sequence(),
+            event: MarketEvent::Snapshot { asks, bids },
+        };
+        (market.subscribe(), snapshot)
+    };
+    forward_updates(socket, snapshot, updates).await;
+}
@@ -1,47 +1,119 @@
-use std::sync::Arc;
+use std::{env, str::FromStr, sync::Arc};
 
 use axum::{
-    debug_handler,
-    extract::{Path, Query, State},
+    extract::ws::{Message, WebSocket},
     http::{header::HeaderMap, StatusCode},
     response::{IntoResponse, Response},
-    routing::{delete, get, post},
-    Json, Router,
+    routing::{get, post},
+    Router,
+};
+use serde::Serialize;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    Mutex,
 };
-use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
 use uuid::Uuid;
 
 pub mod account;
 pub mod market;
 pub mod order;
 
-use market_simulation::{account::AccountId, market::Market};
+use market_simulation::{
+    account::{CostBasis, Margin},
+    fees::{FeeSchedule, Rate},
+    market::{MarkPrice, Market, MarketConfig, MarketError},
+    order::Retention,
+};
 
 type MarketStateHandle = Arc<Mutex<Market>>;
 
 pub async fn app_main() {
     println!("Hello app");
 
-    let market = MarketStateHandle::default();
+    let market = MarketStateHandle::new(Mutex::new(Market::with_config(market_config())));
+
+    tokio::spawn(order::expire_orders(market.clone()));
 
     let api_route = Router::new()
         .route("/api/account/new", post(account::new_account))
         .route("/api/account", get(account::get_account))
+        .route("/api/account/liquidations", get(account::get_liquidations))
+        .route("/api/account/stream", get(account::stream))
         .route(
             "/api/order/:id",
-            get(order::get_order_by_id).delete(order::delete_order_by_id),
+            get(order::get_order_by_id)
+                .delete(order::delete_order_by_id)
+                .patch(order::amend_order_by_id),
         )
+        .route("/api/order/:id/fills", get(order::get_order_fills))
         .route("/api/order/new", post(order::new_order))
         .route("/api/order", get(order::get_all_orders))
         .route("/api/market/bars", get(market::bars))
+        .route("/api/market/book", get(market::book))
+        .route("/api/market/depth", get(market::depth))
         .route("/api/market/quote", get(market::quote))
+        .route("/api/market/stream", get(market::stream))
+        .route("/api/market/trades", get(market::trades))
         .with_state(market)
         .fallback(fallback);
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
     axum::serve(listener, api_route).await.unwrap();
 }
 
+/// Reads the market's settings from the environment, leaving unset ones at their defaults:
+///
+/// - `ORDER_HISTORY_MAX_ORDERS`, `ORDER_HISTORY_MAX_AGE`: completed orders kept, and for how
+///   many seconds
+/// - `MAX_TRADES`: trades kept in the trade log
+/// - `MAX_BARS`: bars kept for each interval
+/// - `INITIAL_MARGIN`, `MAINTENANCE_MARGIN`, `MAX_LEVERAGE`: the default margin model
+/// - `COST_BASIS`: `fifo` or `average_cost`
+/// - `MARK_PRICE`: `last_trade` or `mid`
+/// - `MAKER_FEE_BPS`, `TAKER_FEE_BPS`: flat fees in basis points, negative for a rebate
+fn market_config() -> MarketConfig {
+    let default_margin = Margin::default();
+    let fees = match (env_var("MAKER_FEE_BPS"), env_var("TAKER_FEE_BPS")) {
+        (None, None) => FeeSchedule::default(),
+        (maker, taker) => FeeSchedule::flat(
+            Rate::BasisPoints(maker.unwrap_or(0.)),
+            Rate::BasisPoints(taker.unwrap_or(0.)),
+        ),
+    };
+    MarketConfig {
+        order_history: Retention {
+            max_orders: env_var("ORDER_HISTORY_MAX_ORDERS"),
+            max_age: env_var("ORDER_HISTORY_MAX_AGE"),
+        },
+        max_trades: env_var("MAX_TRADES"),
+        max_bars: env_var("MAX_BARS"),
+        margin: Margin {
+            initial: env_var("INITIAL_MARGIN").unwrap_or(default_margin.initial),
+            maintenance: env_var("MAINTENANCE_MARGIN").unwrap_or(default_margin.maintenance),
+            max_leverage: env_var("MAX_LEVERAGE").or(default_margin.max_leverage),
+        },
+        cost_basis: match env::var("COST_BASIS").as_deref() {
+            Err(_) | Ok("fifo") => CostBasis::Fifo,
+            Ok("average_cost") => CostBasis::AverageCost,
+            Ok(cost_basis) => panic!("COST_BASIS must be fifo or average_cost, not {cost_basis}"),
+        },
+        mark_price: match env::var("MARK_PRICE").as_deref() {
+            Err(_) | Ok("last_trade") => MarkPrice::LastTrade,
+            Ok("mid") => MarkPrice::Mid,
+            Ok(mark_price) => panic!("MARK_PRICE must be last_trade or mid, not {mark_price}"),
+        },
+        fees,
+    }
+}
+
+/// Parses the environment variable `name`, or gives `None` when it is not set
+fn env_var<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => panic!("{name} is not valid: {value}"),
+    }
+}
+
 async fn fallback() -> (StatusCode, &'static str) {
     (StatusCode::NOT_FOUND, "Not found")
 }
@@ -55,6 +127,22 @@ pub enum AppError {
     OrderIdInvalid,
     OrderDoesNotExist,
     OrderCannotBeCancelled,
+    OrderNotOwned,
+    PostOnlyWouldCross,
+    TradeDoesNotExist,
+}
+
+impl From<MarketError> for AppError {
+    fn from(error: MarketError) -> Self {
+        match error {
+            MarketError::PostOnlyWouldCross => AppError::PostOnlyWouldCross,
+            MarketError::OrderDoesNotExist => AppError::OrderDoesNotExist,
+            MarketError::OrderNotOwned => AppError::OrderNotOwned,
+            MarketError::OrderCannotBeCancelled => AppError::OrderCannotBeCancelled,
+            MarketError::OrderInvalid(e) => AppError::OrderInvalid(e),
+            MarketError::TradeDoesNotExist => AppError::TradeDoesNotExist,
+        }
+    }
 }
 
 impl IntoResponse for AppError {
@@ -80,8 +168,20 @@ impl IntoResponse for AppError {
             AppError::OrderCannotBeCancelled => {
                 (StatusCode::GONE, "this order can no longer be cancelled")
             }
+            AppError::OrderNotOwned => (
+                StatusCode::FORBIDDEN,
+                "this order belongs to another account",
+            ),
+            AppError::PostOnlyWouldCross => (
+                StatusCode::CONFLICT,
+                "this post only order would have crossed the book and taken liquidity",
+            ),
+            AppError::TradeDoesNotExist => (
+                StatusCode::NOT_FOUND,
+                "this trade `id` does not exist",
+            ),
         };
-        return (status, message).into_response();
+        (status, message).into_response()
     }
 }
 
@@ -95,3 +195,37 @@ fn parse_account_id_from_header(headers: HeaderMap) -> Result<Uuid, AppError> {
 
     Ok(account_id)
 }
+
+/// Sends `first` then every update from `updates` over `socket` as JSON, until either end closes
+async fn forward_updates<T: Serialize + Clone>(
+    mut socket: WebSocket,
+    first: T,
+    mut updates: broadcast::Receiver<T>,
+) {
+    if send_json(&mut socket, &first).await.is_err() {
+        return;
+    }
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) => {
+                    if send_json(&mut socket, &update).await.is_err() {
+                        break;
+                    }
+                }
+                // the client sees the gap in sequence numbers and can resubscribe
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        }
+    }
+}
+
+async fn send_json(socket: &mut WebSocket, value: &impl Serialize) -> Result<(), axum::Error> {
+    let value = serde_json::to_string(value).expect("updates serialize");
+    socket.send(Message::Text(value)).await
+}
@@ -1,7 +1,8 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use axum::{
-    debug_handler,
     extract::{Json, Path, Query, State},
-    http::{header::HeaderMap, StatusCode},
+    http::header::HeaderMap,
     response::{IntoResponse, Response},
 };
 use serde::{Deserialize, Serialize};
@@ -12,9 +13,42 @@ use market_simulation::order;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OrderReqBody {
-    limit: f64,
+    limit: Option<f64>,
     quantity: usize,
     side: order::Side,
+    #[serde(default)]
+    order_type: order::OrderType,
+    #[serde(default)]
+    time_in_force: order::TimeInForce,
+    post_only: Option<order::PostOnly>,
+    stop_price: Option<f64>,
+    peak: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AmendReqBody {
+    limit: Option<f64>,
+    quantity: Option<usize>,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct OrdersQuery {
+    status: Option<order::Status>,
+    side: Option<order::Side>,
+    from: Option<f64>,
+    to: Option<f64>,
+    /// `next_cursor` of the previous page
+    cursor: Option<u64>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OrdersPage {
+    orders: Vec<order::OrderView>,
+    next_cursor: Option<u64>,
 }
 
 pub async fn get_order_by_id(
@@ -26,31 +60,76 @@ pub async fn get_order_by_id(
 
     let account_id = parse_account_id_from_header(headers)?;
 
-    let mut market = market.lock().await;
+    let market = market.lock().await;
     let account_id = market
         .check_account_uuid(account_id)
         .ok_or(AppError::AccountDoesNotExist)?;
 
-    let order = market
-        .get_order_by_id(order_id)
-        .ok_or(AppError::OrderDoesNotExist)?;
+    let order = market.get_order_by_id(order_id, account_id)?;
 
     Ok(Json(order.view()).into_response())
 }
+pub async fn get_order_fills(
+    headers: HeaderMap,
+    State(market): State<MarketStateHandle>,
+    Path(order_id): Path<String>,
+) -> Result<Response, AppError> {
+    let order_id = Uuid::try_parse(&order_id).map_err(|_| AppError::OrderIdInvalid)?;
+
+    let account_id = parse_account_id_from_header(headers)?;
+
+    let market = market.lock().await;
+    let account_id = market
+        .check_account_uuid(account_id)
+        .ok_or(AppError::AccountDoesNotExist)?;
+
+    let order = market.get_order_by_id(order_id, account_id)?;
+
+    Ok(Json(
+        order
+            .fills()
+            .iter()
+            .map(|fill| fill.view())
+            .collect::<Vec<_>>(),
+    )
+    .into_response())
+}
 pub async fn get_all_orders(
     headers: HeaderMap,
     State(market): State<MarketStateHandle>,
+    Query(orders_query): Query<OrdersQuery>,
 ) -> Result<Response, AppError> {
     let account_id = parse_account_id_from_header(headers)?;
 
-    let mut market = market.lock().await;
+    let market = market.lock().await;
     let account_id = market
         .check_account_uuid(account_id)
         .ok_or(AppError::AccountDoesNotExist)?;
 
-    let orders = market.get_orders_by_account(account_id);
+    let filter = order::OrderFilter {
+        status: orders_query.status,
+        side: orders_query.side,
+        from: orders_query.from,
+        to: orders_query.to,
+    };
+    let limit = orders_query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .min(MAX_PAGE_SIZE);
+    let orders = market.query_orders(account_id, &filter, orders_query.cursor, limit);
+
+    // a full page may be followed by more orders
+    let next_cursor = match orders.last() {
+        Some(order) if orders.len() == limit => Some(order.get_arrival()),
+        _ => None,
+    };
+    let orders = orders.iter().map(|order| order.view()).collect();
 
-    Ok(Json(orders.map(|order| order.view()).collect::<Vec<_>>()).into_response())
+    Ok(Json(OrdersPage {
+        orders,
+        next_cursor,
+    })
+    .into_response())
 }
 pub async fn new_order(
     headers: HeaderMap,
@@ -64,20 +143,68 @@ pub async fn new_order(
         .check_account_uuid(account_id)
         .ok_or(AppError::AccountDoesNotExist)?;
 
-    let order = order::OrderBase::build(
-        order_req_body.limit,
-        order_req_body.quantity,
-        order_req_body.side,
-        account_id,
-    )
-    .map_err(|_| AppError::OrderBodyIncorrect)?;
+    let order = match (order_req_body.order_type, order_req_body.limit) {
+        (order::OrderType::Limit, Some(limit)) => order::OrderBase::build(
+            limit,
+            order_req_body.quantity,
+            order_req_body.side,
+            account_id,
+        ),
+        (order::OrderType::Market, None) => order::OrderBase::build_market(
+            order_req_body.quantity,
+            order_req_body.side,
+            account_id,
+        ),
+        _ => return Err(AppError::OrderBodyIncorrect),
+    }
+    .map_err(|_| AppError::OrderBodyIncorrect)?
+    .with_time_in_force(order_req_body.time_in_force);
+    let order = match order_req_body.post_only {
+        Some(post_only) => order.with_post_only(post_only),
+        None => order,
+    };
+    let order = match order_req_body.stop_price {
+        Some(stop_price) => order
+            .with_stop_price(stop_price)
+            .map_err(|_| AppError::OrderBodyIncorrect)?,
+        None => order,
+    };
+    let order = match order_req_body.peak {
+        Some(peak) => order
+            .with_peak(peak)
+            .map_err(|_| AppError::OrderBodyIncorrect)?,
+        None => order,
+    };
 
-    market.validate_order(&order, account_id).map_err(|e| AppError::OrderInvalid(e))?;
+    // a post only order may have been repriced so report the order as it now stands
+    let (_, order) = market.submit_order(order)?;
+
+    Ok(Json(order).into_response())
+}
+
+pub async fn amend_order_by_id(
+    headers: HeaderMap,
+    State(market): State<MarketStateHandle>,
+    Path(order_id): Path<String>,
+    Json(amend_req_body): Json<AmendReqBody>,
+) -> Result<Response, AppError> {
+    let order_id = Uuid::try_parse(&order_id).map_err(|_| AppError::OrderIdInvalid)?;
 
-    let order_view = order.view();
-    market.handle_incoming_order(order);
+    let account_id = parse_account_id_from_header(headers)?;
 
-    Ok(Json(order_view).into_response())
+    let mut market = market.lock().await;
+    let account_id = market
+        .check_account_uuid(account_id)
+        .ok_or(AppError::AccountDoesNotExist)?;
+
+    let (_, order) = market.amend_order(
+        order_id,
+        account_id,
+        amend_req_body.limit,
+        amend_req_body.quantity,
+    )?;
+
+    Ok(Json(order).into_response())
 }
 
 pub async fn delete_order_by_id(
@@ -94,9 +221,75 @@ pub async fn delete_order_by_id(
         .check_account_uuid(account_id)
         .ok_or(AppError::AccountDoesNotExist)?;
 
-    market
-        .delete_order_by_id(order_id)
-        .ok_or(AppError::OrderCannotBeCancelled)?;
+    market.delete_order_by_id(order_id, account_id)?;
 
     Ok("".into_response())
 }
+
+/// Background task sweeping expired good till date orders out of the book
+pub async fn expire_orders(market: MarketStateHandle) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) else {
+            continue;
+        };
+        market.lock().await.expire_orders(now.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderValue, StatusCode};
+
+    fn headers(account_id: Uuid) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "account-id",
+            HeaderValue::from_str(&account_id.to_string()).unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn order_ownership() {
+        let market = MarketStateHandle::default();
+        let (alice_id, bob_id, order_id) = {
+            let mut market = market.lock().await;
+            let alice_id = market.new_account(1e5, 0).unwrap();
+            let bob_id = market.new_account(1e5, 0).unwrap();
+            let order = order::OrderBase::build(20., 10, order::Side::Bid, alice_id).unwrap();
+            let order_id = order.get_id();
+            market.handle_incoming_order(order).unwrap();
+            (alice_id.as_uuid(), bob_id.as_uuid(), order_id)
+        };
+        let path = || Path(order_id.to_string());
+
+        // Bob can neither see nor cancel Alice's order
+        let response = get_order_by_id(headers(bob_id), State(market.clone()), path()).await;
+        assert_eq!(response.into_response().status(), StatusCode::FORBIDDEN);
+        let response = delete_order_by_id(headers(bob_id), State(market.clone()), path()).await;
+        assert_eq!(response.into_response().status(), StatusCode::FORBIDDEN);
+        let body = Json(AmendReqBody {
+            limit: None,
+            quantity: Some(1),
+        });
+        let response =
+            amend_order_by_id(headers(bob_id), State(market.clone()), path(), body).await;
+        assert_eq!(response.into_response().status(), StatusCode::FORBIDDEN);
+
+        // but Alice can
+        let response = get_order_by_id(headers(alice_id), State(market.clone()), path()).await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+        let response = delete_order_by_id(headers(alice_id), State(market.clone()), path()).await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+        let response = delete_order_by_id(headers(alice_id), State(market.clone()), path()).await;
+        assert_eq!(response.into_response().status(), StatusCode::GONE);
+
+        // and unknown orders are not found
+        let path = Path(Uuid::new_v4().to_string());
+        let response = get_order_by_id(headers(alice_id), State(market.clone()), path).await;
+        assert_eq!(response.into_response().status(), StatusCode::NOT_FOUND);
+    }
+}
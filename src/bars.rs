@@ -0,0 +1,219 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::trade::Trade;
+
+#[derive(Debug, Copy, Clone, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum Interval {
+    #[serde(rename = "1s")]
+    OneSecond,
+    #[default]
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "15m")]
+    FifteenMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl Interval {
+    pub const ALL: [Interval; 6] = [
+        Interval::OneSecond,
+        Interval::OneMinute,
+        Interval::FiveMinutes,
+        Interval::FifteenMinutes,
+        Interval::OneHour,
+        Interval::OneDay,
+    ];
+    pub fn seconds(self) -> f64 {
+        match self {
+            Interval::OneSecond => 1.,
+            Interval::OneMinute => 60.,
+            Interval::FiveMinutes => 300.,
+            Interval::FifteenMinutes => 900.,
+            Interval::OneHour => 3600.,
+            Interval::OneDay => 86400.,
+        }
+    }
+    fn bucket(self, timestamp: f64) -> i64 {
+        (timestamp / self.seconds()).floor() as i64
+    }
+}
+
+/// Open, high, low, close and volume of the trades in one interval
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bar {
+    /// Unix timestamp in seconds at which the interval starts
+    pub start: f64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: usize,
+    pub trade_count: usize,
+}
+
+impl Bar {
+    fn new(start: f64, trade: &Trade) -> Bar {
+        let price = trade.price.into_inner();
+        Bar {
+            start,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: trade.quantity,
+            trade_count: 1,
+        }
+    }
+    fn update(&mut self, trade: &Trade) {
+        let price = trade.price.into_inner();
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += trade.quantity;
+        self.trade_count += 1;
+    }
+}
+
+/// Bars for every supported interval, updated as trades happen
+#[derive(Debug, Default)]
+pub struct Bars {
+    series: HashMap<Interval, BTreeMap<i64, Bar>>,
+    // most bars kept for each interval, unlimited if `None`
+    max_bars: Option<usize>,
+}
+
+impl Bars {
+    pub fn with_max_bars(max_bars: Option<usize>) -> Bars {
+        Bars {
+            max_bars,
+            ..Default::default()
+        }
+    }
+    pub fn update(&mut self, trade: &Trade) {
+        for interval in Interval::ALL {
+            let bucket = interval.bucket(trade.timestamp);
+            let series = self.series.entry(interval).or_default();
+            series
+                .entry(bucket)
+                .and_modify(|bar| bar.update(trade))
+                .or_insert_with(|| Bar::new(bucket as f64 * interval.seconds(), trade));
+            // the oldest bars are dropped first
+            if let Some(max_bars) = self.max_bars {
+                while series.len() > max_bars {
+                    series.pop_first();
+                }
+            }
+        }
+    }
+    /// Bars starting in `[from, to)`, oldest first. Intervals without trades have no bar.
+    pub fn range(
+        &self,
+        interval: Interval,
+        from: Option<f64>,
+        to: Option<f64>,
+    ) -> impl DoubleEndedIterator<Item = &Bar> {
+        let from = from.map_or(i64::MIN, |from| (from / interval.seconds()).ceil() as i64);
+        let to = to.map_or(i64::MAX, |to| (to / interval.seconds()).ceil() as i64);
+        self.series
+            .get(&interval)
+            .into_iter()
+            .flat_map(move |series| series.range(from..to.max(from)))
+            .map(|(_, bar)| bar)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Accounts;
+    use crate::order::{OrderBase, Side};
+    use ordered_float::NotNan;
+
+    fn trade_at(timestamp: f64, price: f64, quantity: usize) -> Trade {
+        let mut accounts = Accounts::default();
+        let account_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bid = OrderBase::build(price, quantity, Side::Bid, account_id).unwrap();
+        let ask = OrderBase::build(price, quantity, Side::Ask, account_id).unwrap();
+        let mut trade = Trade::new(&bid, &ask, NotNan::new(price).unwrap(), quantity);
+        trade.timestamp = timestamp;
+        trade
+    }
+
+    #[test]
+    fn bars_aggregate_trades() {
+        let mut bars = Bars::default();
+        bars.update(&trade_at(60., 10., 5));
+        bars.update(&trade_at(75., 12., 1));
+        bars.update(&trade_at(90., 9., 2));
+        bars.update(&trade_at(119.9, 11., 3));
+        bars.update(&trade_at(120., 11.5, 4));
+
+        let minutes: Vec<&Bar> = bars.range(Interval::OneMinute, None, None).collect();
+        assert_eq!(
+            minutes,
+            vec![
+                &Bar {
+                    start: 60.,
+                    open: 10.,
+                    high: 12.,
+                    low: 9.,
+                    close: 11.,
+                    volume: 11,
+                    trade_count: 4,
+                },
+                &Bar {
+                    start: 120.,
+                    open: 11.5,
+                    high: 11.5,
+                    low: 11.5,
+                    close: 11.5,
+                    volume: 4,
+                    trade_count: 1,
+                },
+            ]
+        );
+
+        assert_eq!(bars.range(Interval::OneSecond, None, None).count(), 5);
+        assert_eq!(bars.range(Interval::FiveMinutes, None, None).count(), 1);
+    }
+    #[test]
+    fn bars_range() {
+        let mut bars = Bars::default();
+        for timestamp in [0., 60., 120., 180.] {
+            bars.update(&trade_at(timestamp, 10., 1));
+        }
+
+        let starts = |from, to| -> Vec<f64> {
+            bars.range(Interval::OneMinute, from, to)
+                .map(|bar| bar.start)
+                .collect()
+        };
+        assert_eq!(starts(Some(60.), Some(180.)), vec![60., 120.]);
+        assert_eq!(starts(Some(30.), None), vec![60., 120., 180.]);
+        assert_eq!(starts(None, Some(60.)), vec![0.]);
+        assert_eq!(starts(Some(180.), Some(60.)), Vec::<f64>::new());
+    }
+    #[test]
+    fn bars_max_bars() {
+        let mut bars = Bars::with_max_bars(Some(2));
+        for timestamp in [0., 1., 60., 61., 120.] {
+            bars.update(&trade_at(timestamp, 10., 1));
+        }
+
+        let starts = |interval| -> Vec<f64> {
+            bars.range(interval, None, None)
+                .map(|bar| bar.start)
+                .collect()
+        };
+        assert_eq!(starts(Interval::OneSecond), vec![61., 120.]);
+        assert_eq!(starts(Interval::OneMinute), vec![60., 120.]);
+        assert_eq!(starts(Interval::OneDay), vec![0.]);
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::account::{AccountId, AccountView, LiquidationView};
+use crate::order::{FillView, OrderView, PriceLevel, Side, Status};
+use crate::trade::PublicTradeView;
+
+// updates a subscriber can fall behind by before it starts missing them
+const CAPACITY: usize = 4096;
+
+/// Something that changed in the market
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum MarketEvent {
+    Trade(PublicTradeView),
+    /// The new state of a price level. A level with no orders has been removed.
+    Depth {
+        side: Side,
+        level: PriceLevel,
+    },
+    /// The best level on each side after a change to either
+    Quote {
+        ask: Option<PriceLevel>,
+        bid: Option<PriceLevel>,
+    },
+    /// Every price level, best first. Only sent to a new subscriber, numbered with the
+    /// sequence of the last update it reflects.
+    Snapshot {
+        asks: Vec<PriceLevel>,
+        bids: Vec<PriceLevel>,
+    },
+}
+
+/// A market event numbered in publishing order. Subscribers that see the sequence skip
+/// have missed updates and should resynchronise from the REST endpoints.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketUpdate {
+    pub sequence: u64,
+    #[serde(flatten)]
+    pub event: MarketEvent,
+}
+
+/// Publishes market updates to any number of subscribers
+#[derive(Debug)]
+pub struct Feed {
+    sender: broadcast::Sender<MarketUpdate>,
+    sequence: u64,
+    quote: (Option<PriceLevel>, Option<PriceLevel>),
+}
+
+impl Default for Feed {
+    fn default() -> Feed {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Feed {
+            sender,
+            sequence: 0,
+            quote: (None, None),
+        }
+    }
+}
+
+impl Feed {
+    pub fn subscribe(&self) -> broadcast::Receiver<MarketUpdate> {
+        self.sender.subscribe()
+    }
+    /// Sequence number of the last update published
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+    pub fn publish(&mut self, event: MarketEvent) {
+        self.sequence += 1;
+        // nobody listening is not an error
+        let _ = self.sender.send(MarketUpdate {
+            sequence: self.sequence,
+            event,
+        });
+    }
+    /// Publishes a quote only if the best levels differ from the last one published
+    pub fn publish_quote(&mut self, ask: Option<PriceLevel>, bid: Option<PriceLevel>) {
+        if self.quote == (ask.clone(), bid.clone()) {
+            return;
+        }
+        self.quote = (ask.clone(), bid.clone());
+        self.publish(MarketEvent::Quote { ask, bid });
+    }
+}
+
+/// Something that happened to one account's orders or holdings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum AccountEvent {
+    /// The market has taken the order on
+    Accepted {
+        order: OrderView,
+    },
+    /// The market refused the order
+    Rejected {
+        order_id: String,
+        reason: String,
+    },
+    Fill {
+        order_id: String,
+        fill: FillView,
+        status: Status,
+        remaining_quantity: usize,
+    },
+    Amended {
+        order: OrderView,
+    },
+    /// The order was cancelled, killed or has expired, as told by its status
+    Cancelled {
+        order: OrderView,
+    },
+    /// The account's balance or position changed
+    Account {
+        account: AccountView,
+    },
+    /// The account fell below its maintenance margin and is being closed out
+    Liquidation {
+        liquidation: LiquidationView,
+    },
+}
+
+/// An account event numbered in publishing order for that account
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountUpdate {
+    pub sequence: u64,
+    #[serde(flatten)]
+    pub event: AccountEvent,
+}
+
+/// Publishes account updates to subscribers of that account only
+#[derive(Debug, Default)]
+pub struct AccountFeeds {
+    feeds: HashMap<AccountId, (broadcast::Sender<AccountUpdate>, u64)>,
+}
+
+impl AccountFeeds {
+    pub fn subscribe(&mut self, account_id: AccountId) -> broadcast::Receiver<AccountUpdate> {
+        self.feeds
+            .entry(account_id)
+            .or_insert_with(|| (broadcast::channel(CAPACITY).0, 0))
+            .0
+            .subscribe()
+    }
+    /// Sequence number of the last update published for `account_id`
+    pub fn sequence(&self, account_id: AccountId) -> u64 {
+        self.feeds
+            .get(&account_id)
+            .map_or(0, |(_, sequence)| *sequence)
+    }
+    /// Accounts nobody has subscribed to are skipped without building their update, and the
+    /// feed of an account whose subscribers have all gone is dropped, numbering from scratch
+    /// for the next subscriber
+    pub fn publish(&mut self, account_id: AccountId, event: impl FnOnce() -> AccountEvent) {
+        if let Some((sender, sequence)) = self.feeds.get_mut(&account_id) {
+            if sender.receiver_count() == 0 {
+                self.feeds.remove(&account_id);
+                return;
+            }
+            *sequence += 1;
+            let _ = sender.send(AccountUpdate {
+                sequence: *sequence,
+                event: event(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn market_update_json() {
+        let update = MarketUpdate {
+            sequence: 7,
+            event: MarketEvent::Depth {
+                side: Side::Bid,
+                level: PriceLevel {
+                    price: 9.5,
+                    quantity: 0,
+                    order_count: 0,
+                },
+            },
+        };
+        let json = serde_json::to_string(&update).unwrap();
+        assert_eq!(
+            json,
+            r#"{"sequence":7,"type":"Depth","side":"Bid","level":{"price":9.5,"quantity":0,"order_count":0}}"#
+        );
+        assert_eq!(serde_json::from_str::<MarketUpdate>(&json).unwrap(), update);
+    }
+    #[test]
+    fn publish_numbers_updates() {
+        let mut feed = Feed::default();
+        // an empty book is not news
+        feed.publish_quote(None, None);
+        // publishing with no subscribers still uses up a sequence number
+        feed.publish(MarketEvent::Quote {
+            ask: None,
+            bid: None,
+        });
+        let mut updates = feed.subscribe();
+        let level = PriceLevel {
+            price: 1.,
+            quantity: 1,
+            order_count: 1,
+        };
+        feed.publish_quote(Some(level.clone()), None);
+        // an unchanged quote is not published again
+        feed.publish_quote(Some(level.clone()), None);
+        assert_eq!(feed.sequence(), 2);
+        assert_eq!(
+            updates.try_recv().unwrap(),
+            MarketUpdate {
+                sequence: 2,
+                event: MarketEvent::Quote {
+                    ask: Some(level),
+                    bid: None,
+                },
+            }
+        );
+        assert!(updates.try_recv().is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+/// Length of the window an account's traded volume is measured over for its fee tier
+pub const VOLUME_WINDOW: f64 = 30. * 24. * 60. * 60.;
+
+/// Whether a fill added liquidity to the book or took it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Liquidity {
+    /// The resting order
+    Maker,
+    /// The incoming order
+    Taker,
+}
+
+/// How much a fill is charged. A negative rate is a rebate.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Rate {
+    /// Hundredths of a percent of the fill's notional
+    BasisPoints(f64),
+    /// A fixed amount for each unit filled
+    PerShare(f64),
+}
+
+impl Rate {
+    pub fn charge(&self, price: f64, quantity: usize) -> f64 {
+        match *self {
+            Rate::BasisPoints(basis_points) => price * quantity as f64 * basis_points / 1e4,
+            Rate::PerShare(per_share) => per_share * quantity as f64,
+        }
+    }
+}
+
+/// The rates paid by accounts that have traded at least `volume` of notional over the last
+/// [`VOLUME_WINDOW`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Tier {
+    pub volume: f64,
+    pub maker: Rate,
+    pub taker: Rate,
+}
+
+/// Fees charged on every fill, by liquidity and the account's recent volume.
+/// The default schedule charges nothing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeeSchedule {
+    // ascending by volume
+    tiers: Vec<Tier>,
+}
+
+impl FeeSchedule {
+    /// The same rates whatever an account has traded
+    pub fn flat(maker: Rate, taker: Rate) -> FeeSchedule {
+        FeeSchedule::tiered(vec![Tier {
+            volume: 0.,
+            maker,
+            taker,
+        }])
+    }
+    /// An account pays the rates of the highest tier whose volume it has reached, and
+    /// nothing until it reaches the lowest
+    pub fn tiered(mut tiers: Vec<Tier>) -> FeeSchedule {
+        tiers.sort_by(|a, b| a.volume.total_cmp(&b.volume));
+        FeeSchedule { tiers }
+    }
+    /// Fee for a fill of `quantity` at `price` by an account that has traded `volume` of
+    /// notional over the last [`VOLUME_WINDOW`], not counting this fill
+    pub fn fee(&self, volume: f64, liquidity: Liquidity, price: f64, quantity: usize) -> f64 {
+        let Some(tier) = self.tiers.iter().rev().find(|tier| tier.volume <= volume) else {
+            return 0.;
+        };
+        match liquidity {
+            Liquidity::Maker => tier.maker.charge(price, quantity),
+            Liquidity::Taker => tier.taker.charge(price, quantity),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fee_schedule() {
+        assert_eq!(
+            FeeSchedule::default().fee(0., Liquidity::Taker, 10., 100),
+            0.
+        );
+
+        let flat = FeeSchedule::flat(Rate::BasisPoints(-1.), Rate::PerShare(0.01));
+        assert_eq!(flat.fee(0., Liquidity::Maker, 10., 100), -0.1);
+        assert_eq!(flat.fee(0., Liquidity::Taker, 10., 100), 1.);
+
+        let tiered = FeeSchedule::tiered(vec![
+            Tier {
+                volume: 1e6,
+                maker: Rate::BasisPoints(0.),
+                taker: Rate::BasisPoints(2.),
+            },
+            Tier {
+                volume: 0.,
+                maker: Rate::BasisPoints(1.),
+                taker: Rate::BasisPoints(5.),
+            },
+        ]);
+        assert_eq!(tiered.fee(0., Liquidity::Taker, 100., 100), 5.);
+        assert_eq!(tiered.fee(999_999., Liquidity::Maker, 100., 100), 1.);
+        assert_eq!(tiered.fee(1e6, Liquidity::Taker, 100., 100), 2.);
+        assert_eq!(tiered.fee(1e6, Liquidity::Maker, 100., 100), 0.);
+    }
+}
@@ -1,3 +1,7 @@
 pub mod account;
+pub mod bars;
+pub mod feed;
+pub mod fees;
 pub mod market;
 pub mod order;
+pub mod trade;
@@ -1,9 +1,3 @@
-use std::net::SocketAddr;
-use std::process;
-use std::sync::Arc;
-use std::thread;
-use std::time;
-
 use tokio::runtime::Builder;
 
 mod app;
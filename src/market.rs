@@ -1,19 +1,107 @@
 use std::cmp::min;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use ordered_float::NotNan;
+use tokio::sync::broadcast;
 use uuid::Uuid;
 
-use crate::account::{Account, AccountId, Accounts};
-use crate::order::{OrderBase, OrderBook, ProcessedOrders, Status, Side};
+use crate::account::{AccountId, AccountView, Accounts, CostBasis, Liquidation, Margin};
+use crate::bars::{Bar, Bars, Interval};
+use crate::feed::{AccountEvent, AccountFeeds, AccountUpdate, Feed, MarketEvent, MarketUpdate};
+use crate::fees::FeeSchedule;
+use crate::order::{
+    BookEntryView, OrderBase, OrderBook, OrderFilter, OrderHistory, OrderType, OrderView, PostOnly,
+    PriceLevel, Retention, Side, Status, TimeInForce, TriggerBook, TICK_SIZE,
+};
+use crate::trade::{Trade, TradeLog};
+
+/// Reasons the market refuses to act on an order
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MarketError {
+    /// A post only order would have taken liquidity
+    PostOnlyWouldCross,
+    /// No live order has this id
+    OrderDoesNotExist,
+    /// The order belongs to another account
+    OrderNotOwned,
+    /// The order has already been executed, cancelled or expired
+    OrderCannotBeCancelled,
+    OrderInvalid(&'static str),
+    /// No trade in the log has this id
+    TradeDoesNotExist,
+}
+
+impl fmt::Display for MarketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            MarketError::PostOnlyWouldCross => "Post only order would cross the book",
+            MarketError::OrderDoesNotExist => "Order does not exist",
+            MarketError::OrderNotOwned => "Order belongs to another account",
+            MarketError::OrderCannotBeCancelled => "Order can no longer be cancelled",
+            MarketError::OrderInvalid(reason) => reason,
+            MarketError::TradeDoesNotExist => "Trade does not exist",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Price accounts are valued at when they are reported. Margin checks and liquidations
+/// always use the last trade price.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum MarkPrice {
+    #[default]
+    LastTrade,
+    /// Halfway between the best bid and ask, or the last trade price while a side is empty
+    Mid,
+}
+
+/// Settings a market is created with
+#[derive(Debug, Clone, Default)]
+pub struct MarketConfig {
+    /// How long executed, cancelled and expired orders stay queryable
+    pub order_history: Retention,
+    /// Most trades kept in the trade log, oldest are dropped first
+    pub max_trades: Option<usize>,
+    /// Most bars kept for each interval, oldest are dropped first
+    pub max_bars: Option<usize>,
+    pub margin: Margin,
+    pub cost_basis: CostBasis,
+    pub mark_price: MarkPrice,
+    pub fees: FeeSchedule,
+}
 
 #[derive(Debug, Default)]
 pub struct Market {
     order_book: OrderBook,
     accounts: Accounts,
-    processed_orders: ProcessedOrders,
+    order_history: OrderHistory,
+    trigger_book: TriggerBook,
+    trades: TradeLog,
+    bars: Bars,
+    last_price: Option<NotNan<f64>>,
+    // last sequence number handed out, see `next_sequence`
+    sequence: u64,
+    feed: Feed,
+    account_feeds: AccountFeeds,
+    // set while closing orders are being submitted, see `liquidate`
+    liquidating: bool,
+    mark_price: MarkPrice,
 }
 
 impl Market {
+    pub fn with_config(config: MarketConfig) -> Market {
+        Market {
+            order_history: OrderHistory::with_retention(config.order_history),
+            trades: TradeLog::with_max_trades(config.max_trades),
+            bars: Bars::with_max_bars(config.max_bars),
+            accounts: Accounts::with_margin(config.margin)
+                .with_cost_basis(config.cost_basis)
+                .with_fees(config.fees),
+            mark_price: config.mark_price,
+            ..Default::default()
+        }
+    }
     pub fn new_account(
         &mut self,
         account_balance: f64,
@@ -24,6 +112,14 @@ impl Market {
         };
         Ok(self.accounts.create_new_account(account_balance, position))
     }
+    /// See [`Accounts::set_max_leverage`]
+    pub fn set_max_leverage(
+        &mut self,
+        account_id: AccountId,
+        max_leverage: Option<f64>,
+    ) -> Result<(), &'static str> {
+        self.accounts.set_max_leverage(account_id, max_leverage)
+    }
     pub fn validate_order(
         &self,
         order: &OrderBase,
@@ -31,122 +127,746 @@ impl Market {
     ) -> Result<(), &'static str> {
         const MAX_ORDERS: usize = 10;
 
-        let curr_orders = self.order_book.filter_order_by_account(account_id);
         // sufficient account_balance
-        let notional = order.limit * order.quantity as f64;
-        if !self
-            .accounts
-            .check_sufficient_balance(account_id, &order)
-        {
+        let (price, quantity) = match (order.order_type, order.stop_price) {
+            (OrderType::Limit, _) => (order.limit, order.quantity),
+            // a stop market order will trade around its stop price
+            (OrderType::Market, Some(stop_price)) => (stop_price, order.quantity),
+            // a market order has no limit so estimate its cost by walking the book
+            (OrderType::Market, None) => {
+                let (filled, notional) = self.order_book.sweep(order);
+                let price = if filled == 0 { 0. } else { notional / filled as f64 };
+                (NotNan::new(price).map_err(|_| "Invalid order price")?, filled)
+            }
+        };
+        if !self.accounts.check_sufficient_balance(
+            account_id,
+            order.side,
+            price,
+            quantity,
+            self.last_price,
+        ) {
             return Err("Insufficient account balance");
         }
 
-        let mut num_orders = 0;
-        // no wash trades
-        match order.side {
-            Side::Bid => if curr_orders.inspect(|_| num_orders+=1).filter(|order| order.side == Side::Ask).filter(|o| o.limit <= order.limit).peekable().peek().is_some() {
-                return Err("Wash trades are not allowed")
-            }
-            Side::Ask => if curr_orders.inspect(|_| num_orders+=1).filter(|order| order.side == Side::Bid).filter(|o| o.limit >= order.limit).peekable().peek().is_some() {
-                return Err("Wash trades are not allowed")
+        // good till date orders must expire in the future
+        if let TimeInForce::GoodTillDate(expiry) = order.time_in_force {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|_| "System clock is before the epoch")?
+                .as_secs_f64();
+            if !expiry.is_finite() || expiry <= now {
+                return Err("Expiry is in the past");
             }
         }
 
+        // post only orders have to be able to rest in the book
+        if order.post_only.is_some() && !order.can_rest() {
+            return Err("Post only orders must be limit orders that can rest in the book");
+        }
+
+        // no wash trades, including against stops that have yet to trigger
+        let curr_orders: Vec<&OrderBase> = self
+            .order_book
+            .filter_order_by_account(account_id)
+            .chain(self.trigger_book.filter_order_by_account(account_id))
+            .collect();
+        if curr_orders
+            .iter()
+            .any(|resting| is_wash_trade(order, resting))
+        {
+            return Err("Wash trades are not allowed");
+        }
+
         // cap on outstanding orders
-        if num_orders >= MAX_ORDERS {
+        if curr_orders.len() >= MAX_ORDERS {
             return Err("Exceeds maximum number of outstanding orders allowed");
         }
         Ok(())
     }
-    pub fn get_order_by_id(&self, order_id: Uuid) -> Option<&OrderBase> {
-        if let Some(order) = self.processed_orders.find_order(order_id) {
+    /// Looks up an order, live or processed, on behalf of `account_id`
+    pub fn get_order_by_id(
+        &self,
+        order_id: Uuid,
+        account_id: AccountId,
+    ) -> Result<&OrderBase, MarketError> {
+        let order = self
+            .find_order(order_id)
+            .ok_or(MarketError::OrderDoesNotExist)?;
+        if order.account_id != account_id {
+            return Err(MarketError::OrderNotOwned);
+        }
+        Ok(order)
+    }
+    fn find_order(&self, order_id: Uuid) -> Option<&OrderBase> {
+        if let Some(order) = self.order_history.find_order(order_id) {
             return Some(order);
         }
         if let Some(order) = self.order_book.find_order(order_id) {
             return Some(order);
         }
-        return None;
+        if let Some(order) = self.trigger_book.find_order(order_id) {
+            return Some(order);
+        }
+        None
+    }
+    /// Cancels a live order on behalf of `account_id`
+    pub fn delete_order_by_id(
+        &mut self,
+        order_id: Uuid,
+        account_id: AccountId,
+    ) -> Result<(), MarketError> {
+        let order = self.get_order_by_id(order_id, account_id)?;
+        if self.order_history.find_order(order.get_id()).is_some() {
+            return Err(MarketError::OrderCannotBeCancelled);
+        }
+        let mut order = self
+            .withdraw(order_id)
+            .expect("an unprocessed order is in the order book or trigger book");
+        order.set_status(Status::Cancelled);
+        self.notify_cancelled(&order);
+        self.order_history.push(order);
+        self.publish(&[]);
+        Ok(())
     }
-    pub fn delete_order_by_id(&mut self, order_id: Uuid) -> Option<()> {
-        let mut order = self.order_book.delete_order(order_id)?;
-        order.status = Status::Cancelled;
-        self.processed_orders.push(order);
-        Some(())
+    /// Moves good till date orders whose expiry is at or before `now` out of the books.
+    /// Returns the number of orders expired.
+    pub fn expire_orders(&mut self, now: f64) -> usize {
+        let expired: Vec<Uuid> = self
+            .order_book
+            .iter()
+            .chain(self.trigger_book.iter())
+            .filter(|order| order.is_expired(now))
+            .map(|order| order.get_id())
+            .collect();
+        for order_id in expired.iter() {
+            if let Some(mut order) = self.withdraw(*order_id) {
+                order.set_status(Status::Expired);
+                self.notify_cancelled(&order);
+                self.order_history.push(order);
+            }
+        }
+        self.order_history.prune(now);
+        if !expired.is_empty() {
+            self.publish(&[]);
+        }
+        expired.len()
+    }
+    /// Changes the limit and/or remaining quantity of a live order.
+    /// Reducing the quantity keeps the order's time priority. Changing the price or increasing
+    /// the quantity re-validates the order and sends it to the back of the queue, where it may
+    /// trade immediately if the new price crosses the book.
+    /// Returns the trades it produced along with the amended order as it then stands.
+    pub fn amend_order(
+        &mut self,
+        order_id: Uuid,
+        account_id: AccountId,
+        limit: Option<f64>,
+        quantity: Option<usize>,
+    ) -> Result<(Vec<Trade>, OrderView), MarketError> {
+        let current = self
+            .order_book
+            .find_order(order_id)
+            .or_else(|| self.trigger_book.find_order(order_id))
+            .ok_or(MarketError::OrderDoesNotExist)?;
+        if current.account_id != account_id {
+            return Err(MarketError::OrderNotOwned);
+        }
+        let limit = limit
+            .map(NotNan::new)
+            .transpose()
+            .map_err(|_| MarketError::OrderInvalid("Invalid limit price"))?;
+        if limit.is_some() && current.order_type == OrderType::Market {
+            return Err(MarketError::OrderInvalid("Market orders have no limit"));
+        }
+        if quantity == Some(0) {
+            return Err(MarketError::OrderInvalid("Quantity must be positive"));
+        }
+
+        let keeps_priority = limit.is_none_or(|limit| limit == current.limit)
+            && quantity.is_none_or(|quantity| quantity <= current.quantity);
+        let mut amended = current.clone();
+        if let Some(limit) = limit {
+            amended.limit = limit;
+        }
+        if let Some(quantity) = quantity {
+            amended.set_quantity(quantity);
+        }
+        amended.touch();
+        let is_stop = self.trigger_book.find_order(order_id).is_some();
+        // the old order's reservation is released before the amended one is validated
+        let original = self.withdraw(order_id).expect("order was found above");
+
+        if keeps_priority {
+            self.notify_amended(&amended);
+            let view = amended.view();
+            if is_stop {
+                self.park(amended);
+            } else {
+                self.rest(amended);
+            }
+            self.publish(&[]);
+            return Ok((Vec::new(), view));
+        }
+
+        let result = self
+            .validate_order(&amended, account_id)
+            .map_err(MarketError::OrderInvalid)
+            .and_then(|_| {
+                if is_stop {
+                    Ok(())
+                } else {
+                    self.check_post_only(&mut amended)
+                }
+            });
+        if let Err(error) = result {
+            if is_stop {
+                self.park(original);
+            } else {
+                self.rest(original);
+            }
+            return Err(error);
+        }
+        amended.reveal();
+        amended.set_sequence(self.next_sequence());
+        self.notify_amended(&amended);
+        if is_stop {
+            let view = amended.view();
+            self.park(amended);
+            return Ok((Vec::new(), view));
+        }
+        Ok(self.execute(amended))
     }
     pub fn get_orders_by_account(&self, account_id: AccountId) -> impl Iterator<Item = &OrderBase> {
         self.order_book
             .filter_order_by_account(account_id)
-            .into_iter()
-            .chain(
-                self.processed_orders
-                    .filter_order_by_account(account_id)
-                    .into_iter(),
-            )
+            .chain(self.trigger_book.filter_order_by_account(account_id))
+            .chain(self.order_history.filter_order_by_account(account_id, None))
+    }
+    /// Up to `limit` orders of `account_id`, live or completed, that match `filter` and
+    /// arrived after `cursor`, oldest first
+    pub fn query_orders(
+        &self,
+        account_id: AccountId,
+        filter: &OrderFilter,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> Vec<&OrderBase> {
+        let mut orders: Vec<&OrderBase> = self
+            .order_book
+            .filter_order_by_account(account_id)
+            .chain(self.trigger_book.filter_order_by_account(account_id))
+            .filter(|order| cursor.is_none_or(|cursor| order.get_arrival() > cursor))
+            .filter(|order| filter.matches(order))
+            .collect();
+        orders.extend(
+            self.order_history
+                .filter_order_by_account(account_id, cursor)
+                .filter(|order| filter.matches(order))
+                .take(limit),
+        );
+        orders.sort_by_key(|order| order.get_arrival());
+        orders.truncate(limit);
+        orders
     }
     pub fn check_account_uuid(&self, uuid: Uuid) -> Option<AccountId> {
         self.accounts.check_uuid(uuid)
     }
-    pub fn get_account(&self, account_id: &AccountId) -> &Account {
-        self.accounts.get(&account_id)
+    /// The account's liquidations, oldest first
+    pub fn liquidations(&self, account_id: &AccountId) -> &[Liquidation] {
+        self.accounts.liquidations(account_id)
+    }
+    /// The account with its equity, margins and P&L valued at the mark price
+    pub fn account_view(&self, account_id: &AccountId) -> AccountView {
+        self.accounts.view(account_id, self.mark())
+    }
+    /// See [`MarkPrice`]
+    pub fn mark(&self) -> Option<NotNan<f64>> {
+        match (self.mark_price, self.quote()) {
+            (MarkPrice::Mid, (Some(ask), Some(bid))) => Some((ask.limit + bid.limit) / 2.),
+            _ => self.last_price,
+        }
     }
     pub fn quote(&self) -> (Option<&OrderBase>, Option<&OrderBase>) { 
         (self.order_book.peek(Side::Ask), self.order_book.peek(Side::Bid))
     }
-    pub fn handle_incoming_order(&mut self, mut order: OrderBase) {
+    /// Aggregated price levels of the book as (asks, bids), best price first
+    pub fn depth(&self, levels: Option<usize>) -> (Vec<PriceLevel>, Vec<PriceLevel>) {
+        (
+            self.order_book.depth(Side::Ask, levels),
+            self.order_book.depth(Side::Bid, levels),
+        )
+    }
+    /// Every resting order as (asks, bids) in priority order, see [`OrderBook::snapshot`]
+    pub fn book(&self, viewer: Option<AccountId>) -> (Vec<BookEntryView>, Vec<BookEntryView>) {
+        (
+            self.order_book.snapshot(Side::Ask, viewer),
+            self.order_book.snapshot(Side::Bid, viewer),
+        )
+    }
+    /// Receives every market update published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<MarketUpdate> {
+        self.feed.subscribe()
+    }
+    /// Sequence number of the last market update published
+    pub fn feed_sequence(&self) -> u64 {
+        self.feed.sequence()
+    }
+    /// Receives every update about `account_id` published from now on
+    pub fn subscribe_account(
+        &mut self,
+        account_id: AccountId,
+    ) -> broadcast::Receiver<AccountUpdate> {
+        self.account_feeds.subscribe(account_id)
+    }
+    /// Sequence number of the last update published about `account_id`
+    pub fn account_feed_sequence(&self, account_id: AccountId) -> u64 {
+        self.account_feeds.sequence(account_id)
+    }
+    pub fn trades(&self) -> &[Trade] {
+        self.trades.as_slice()
+    }
+    /// Up to `limit` trades following the trade `since`, or the latest `limit` trades when
+    /// `since` is not given, oldest first. A trade dropped from the log no longer exists.
+    pub fn trades_since(&self, since: Option<Uuid>, limit: usize) -> Result<&[Trade], MarketError> {
+        self.trades
+            .since(since, limit)
+            .ok_or(MarketError::TradeDoesNotExist)
+    }
+    /// Up to `limit` OHLCV bars starting in `[from, to)`, oldest first. These are the first
+    /// bars from `from`, or the latest bars before `to` when `from` is not given.
+    pub fn bars(
+        &self,
+        interval: Interval,
+        from: Option<f64>,
+        to: Option<f64>,
+        limit: usize,
+    ) -> Vec<Bar> {
+        let bars = self.bars.range(interval, from, to);
+        if from.is_some() {
+            return bars.take(limit).copied().collect();
+        }
+        let mut bars: Vec<Bar> = bars.rev().take(limit).copied().collect();
+        bars.reverse();
+        bars
+    }
+    pub fn last_price(&self) -> Option<NotNan<f64>> {
+        self.last_price
+    }
+    /// Matches `order` against the book, resting any remainder its time in force allows.
+    /// Stop orders are held in the trigger book until the last trade price reaches them,
+    /// and any stops released by this order's trades are matched within the same call.
+    /// Returns the trades it produced, in execution order.
+    pub fn handle_incoming_order(&mut self, order: OrderBase) -> Result<Vec<Trade>, MarketError> {
+        self.accept_order(order).map(|(trades, _)| trades)
+    }
+    /// Validates `order` and hands it to [`Market::handle_incoming_order`], telling the
+    /// order's account if it is rejected. Returns the trades along with the order as it
+    /// stands afterwards, which may no longer be kept once it is pruned from the history.
+    pub fn submit_order(
+        &mut self,
+        order: OrderBase,
+    ) -> Result<(Vec<Trade>, OrderView), MarketError> {
+        if let Err(reason) = self.validate_order(&order, order.account_id) {
+            let error = MarketError::OrderInvalid(reason);
+            self.notify_rejected(&order, error);
+            return Err(error);
+        }
+        self.accept_order(order)
+    }
+    fn accept_order(
+        &mut self,
+        mut order: OrderBase,
+    ) -> Result<(Vec<Trade>, OrderView), MarketError> {
+        order.set_status(Status::Pending);
+        let sequence = self.next_sequence();
+        order.set_arrival(sequence);
+
+        if order.stop_price.is_some() {
+            match self.last_price {
+                Some(last_price) if order.is_triggered(last_price) => {
+                    order.set_status(Status::Triggered);
+                }
+                _ => {
+                    self.notify_accepted(&order);
+                    let view = order.view();
+                    self.park(order);
+                    return Ok((Vec::new(), view));
+                }
+            }
+        }
+        if let Err(error) = self.check_post_only(&mut order) {
+            self.notify_rejected(&order, error);
+            return Err(error);
+        }
+        self.notify_accepted(&order);
+        Ok(self.execute(order))
+    }
+    /// Time priority comes from these rather than the wall clock so that matching is
+    /// reproducible, even for orders built within the clock's resolution of each other
+    fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
+    }
+    /// Matches an order that has passed its arrival checks, then any stops its trades release
+    fn execute(&mut self, order: OrderBase) -> (Vec<Trade>, OrderView) {
+        let order_id = order.get_id();
+        let (mut trades, view) = self.match_order(order);
+
+        // each trade moves the last price, possibly through more stops
+        while let Some(mut triggered) = self
+            .last_price
+            .and_then(|last_price| self.trigger_book.pop_triggered(last_price))
+        {
+            self.release(&triggered);
+            self.notify_account(triggered.account_id);
+            triggered.set_status(Status::Triggered);
+            // a released stop queues behind orders already resting at its limit
+            triggered.set_sequence(self.next_sequence());
+            // the account may since have placed orders that the stop would trade against
+            let accepted = self.check_post_only(&mut triggered).is_ok()
+                && !self
+                    .order_book
+                    .filter_order_by_account(triggered.account_id)
+                    .any(|resting| is_wash_trade(&triggered, resting));
+            if accepted {
+                trades.extend(self.match_order(triggered).0);
+            } else {
+                triggered.set_status(Status::Cancelled);
+                self.notify_cancelled(&triggered);
+                self.order_history.push(triggered);
+            }
+        }
+        for trade in trades.iter() {
+            self.bars.update(trade);
+            self.trades.push(trade.clone());
+        }
+        self.publish(&trades);
+        if !trades.is_empty() {
+            self.liquidate();
+        }
+        // released stops and liquidations may have traded against the order since
+        let view = self
+            .find_order(order_id)
+            .map(OrderBase::view)
+            .unwrap_or(view);
+        (trades, view)
+    }
+    /// Closes out every account under its maintenance margin at the last trade price.
+    /// The closing orders move the price themselves, so accounts are checked again until
+    /// a round of liquidations trades nothing.
+    fn liquidate(&mut self) {
+        // closing orders are executed from within this loop, which checks their trades too
+        if self.liquidating {
+            return;
+        }
+        self.liquidating = true;
+        while let Some(mark) = self.last_price {
+            let mut traded = false;
+            for account_id in self.accounts.under_margin(mark) {
+                traded |= !self.liquidate_account(account_id).is_empty();
+            }
+            if !traded {
+                break;
+            }
+        }
+        self.liquidating = false;
+    }
+    /// Cancels the account's live orders and sends a market order closing its position,
+    /// unless earlier liquidations have moved the price back above its maintenance margin.
+    /// Nothing is touched while no other account's order is there to close against.
+    fn liquidate_account(&mut self, account_id: AccountId) -> Vec<Trade> {
+        let Some(mark) = self
+            .last_price
+            .filter(|mark| self.accounts.is_under_margin(account_id, *mark))
+        else {
+            return Vec::new();
+        };
+        let account = self.accounts.view(&account_id, Some(mark));
+        let side = if account.position > 0 {
+            Side::Ask
+        } else {
+            Side::Bid
+        };
+        // the account is tried again after every trade, which must not keep cancelling
+        // its orders and sending market orders that close nothing
+        if !self
+            .order_book
+            .iter_by_priority(-side)
+            .any(|counter| counter.account_id != account_id)
+        {
+            return Vec::new();
+        }
+        let cancelled_orders: Vec<Uuid> = self
+            .order_book
+            .filter_order_by_account(account_id)
+            .chain(self.trigger_book.filter_order_by_account(account_id))
+            .map(|order| order.get_id())
+            .collect();
+        for order_id in cancelled_orders.iter() {
+            let mut order = self.withdraw(*order_id).expect("order was found above");
+            order.set_status(Status::Cancelled);
+            self.notify_cancelled(&order);
+            self.order_history.push(order);
+        }
+
+        let order =
+            OrderBase::build_market(account.position.unsigned_abs() as usize, side, account_id)
+                .expect("an account under margin has a position to close");
+        let liquidation = Liquidation {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs_f64())
+                .unwrap_or_default(),
+            mark_price: mark,
+            equity: account
+                .equity
+                .expect("equity is known once there is a mark"),
+            maintenance_margin: account
+                .maintenance_margin
+                .expect("margin is known once there is a mark"),
+            position: account.position,
+            cancelled_orders,
+            order_id: order.get_id(),
+        };
+        let trades = self
+            .handle_incoming_order(order)
+            .expect("market orders are never post only");
+        self.notify_liquidation(account_id, &liquidation);
+        self.accounts.record_liquidation(account_id, liquidation);
+        trades
+    }
+    /// Tells feed subscribers about `trades` and every change to the book since the last call
+    fn publish(&mut self, trades: &[Trade]) {
+        for trade in trades {
+            self.feed.publish(MarketEvent::Trade(trade.public_view()));
+        }
+        for (side, level) in self.order_book.take_changed_levels() {
+            self.feed.publish(MarketEvent::Depth { side, level });
+        }
+        let ask = self.order_book.depth(Side::Ask, Some(1)).pop();
+        let bid = self.order_book.depth(Side::Bid, Some(1)).pop();
+        self.feed.publish_quote(ask, bid);
+    }
+    fn notify_accepted(&mut self, order: &OrderBase) {
+        self.account_feeds
+            .publish(order.account_id, || AccountEvent::Accepted {
+                order: order.view(),
+            });
+    }
+    fn notify_rejected(&mut self, order: &OrderBase, error: MarketError) {
+        self.account_feeds
+            .publish(order.account_id, || AccountEvent::Rejected {
+                order_id: order.get_id().to_string(),
+                reason: error.to_string(),
+            });
+    }
+    fn notify_amended(&mut self, order: &OrderBase) {
+        self.account_feeds
+            .publish(order.account_id, || AccountEvent::Amended {
+                order: order.view(),
+            });
+    }
+    fn notify_cancelled(&mut self, order: &OrderBase) {
+        self.account_feeds
+            .publish(order.account_id, || AccountEvent::Cancelled {
+                order: order.view(),
+            });
+    }
+    fn notify_fill(&mut self, order: &OrderBase) {
+        self.account_feeds
+            .publish(order.account_id, || AccountEvent::Fill {
+                order_id: order.get_id().to_string(),
+                fill: order.fills().last().expect("order was just filled").view(),
+                status: order.status,
+                remaining_quantity: order.quantity,
+            });
+    }
+    fn notify_account(&mut self, account_id: AccountId) {
+        let (accounts, mark) = (&self.accounts, self.mark());
+        self.account_feeds
+            .publish(account_id, || AccountEvent::Account {
+                account: accounts.view(&account_id, mark),
+            });
+    }
+    /// Puts `order` in the order book, reserving what it could use from its account
+    fn rest(&mut self, order: OrderBase) {
+        let account_id = order.account_id;
+        self.reserve(&order);
+        self.order_book.insert_order(order);
+        self.notify_account(account_id);
+    }
+    /// Puts a stop `order` in the trigger book, reserving what it could use from its account
+    fn park(&mut self, order: OrderBase) {
+        let account_id = order.account_id;
+        self.reserve(&order);
+        self.trigger_book.insert_order(order);
+        self.notify_account(account_id);
+    }
+    /// Takes a live order out of whichever book holds it and releases its reservation
+    fn withdraw(&mut self, order_id: Uuid) -> Option<OrderBase> {
+        let order = self
+            .order_book
+            .delete_order(order_id)
+            .or_else(|| self.trigger_book.delete_order(order_id))?;
+        self.release(&order);
+        self.notify_account(order.account_id);
+        Some(order)
+    }
+    fn reserve(&mut self, order: &OrderBase) {
+        self.accounts.reserve(
+            order.account_id,
+            order.side,
+            Self::reservation_price(order),
+            order.quantity,
+        );
+    }
+    fn release(&mut self, order: &OrderBase) {
+        self.accounts.release(
+            order.account_id,
+            order.side,
+            Self::reservation_price(order),
+            order.quantity,
+        );
+    }
+    /// Price a live order is reserved at. Only stop market orders are live without a limit,
+    /// and they are reserved at their stop price as in `validate_order`.
+    fn reservation_price(order: &OrderBase) -> NotNan<f64> {
+        match (order.order_type, order.stop_price) {
+            (OrderType::Market, Some(stop_price)) => stop_price,
+            _ => order.limit,
+        }
+    }
+    fn notify_liquidation(&mut self, account_id: AccountId, liquidation: &Liquidation) {
+        self.account_feeds
+            .publish(account_id, || AccountEvent::Liquidation {
+                liquidation: liquidation.view(),
+            });
+    }
+    /// Post only orders must never take liquidity
+    fn check_post_only(&self, order: &mut OrderBase) -> Result<(), MarketError> {
         let side = order.side;
-        order.status = Status::Pending;
-        let order = loop {
+        if let (Some(post_only), Some(counter)) = (order.post_only, self.order_book.peek(-side)) {
+            if order.crosses(counter) {
+                match post_only {
+                    PostOnly::Reject => return Err(MarketError::PostOnlyWouldCross),
+                    PostOnly::Reprice => {
+                        let ticks = (counter.limit.into_inner() / TICK_SIZE).round()
+                            - f64::from(side as i32);
+                        order.limit = NotNan::new(ticks / TICK_SIZE.recip())
+                            .expect("resting orders have a finite limit");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Returns the order's trades along with how it was left, resting or completed
+    fn match_order(&mut self, mut order: OrderBase) -> (Vec<Trade>, OrderView) {
+        let side = order.side;
+        let mut trades = Vec::new();
+
+        // fill or kill orders are killed before any account is touched
+        if order.time_in_force == TimeInForce::FillOrKill
+            && self.order_book.sweep(&order).0 < order.quantity
+        {
+            order.set_status(Status::Cancelled);
+            self.notify_cancelled(&order);
+            let view = order.view();
+            self.order_history.push(order);
+            return (trades, view);
+        }
+        let mut order = loop {
             let best_counter = self.order_book.peek(-side);
             match best_counter {
                 Some(counter) => {
-                    if counter.limit * f64::from(side as i32) > order.limit * f64::from(side as i32)
-                    {
-                        break Some(order);
+                    if !order.crosses(counter) {
+                        break order;
                     }
                 }
-                None => break Some(order),
+                None => break order,
             }
 
             let mut matched = self.order_book.pop(-side).unwrap();
+            self.release(&matched);
             let aggressor_id = order.account_id;
             let counterparty_id = matched.account_id;
-            let transaction_quantity = min(order.quantity, matched.quantity);
+            // only the displayed slice of an iceberg order can be taken at once
+            let transaction_quantity = min(order.quantity, matched.displayed_quantity());
+            // the resting order sets the execution price
+            let transaction_price = matched.limit;
 
-            self.accounts.handle_transaction(
+            let mut trade = Trade::new(&order, &matched, transaction_price, transaction_quantity);
+            (trade.aggressor_fee, trade.passive_fee) = self.accounts.handle_transaction(
                 aggressor_id,
                 counterparty_id,
                 side,
-                f64::from(order.limit),
+                f64::from(transaction_price),
                 transaction_quantity,
+                trade.timestamp,
             );
+            self.last_price = Some(transaction_price);
 
-            if matched.quantity == transaction_quantity {
-                matched.status = Status::Executed;
-                self.processed_orders.push(matched);
+            matched.fill(&trade);
+            order.fill(&trade);
+            self.notify_fill(&matched);
+            self.notify_fill(&order);
+            if matched.quantity == 0 {
+                self.order_history.push(matched);
             } else {
-                matched.quantity -= transaction_quantity;
+                if matched.displayed_quantity() == 0 {
+                    matched.replenish(self.next_sequence());
+                }
+                // rather than `rest`, as both accounts are notified of the trade below
+                self.reserve(&matched);
                 self.order_book.insert_order(matched);
             }
-            if order.quantity == transaction_quantity {
-                order.status = Status::Executed;
-                self.processed_orders.push(order);
-                break None;
-            } else {
-                order.quantity -= transaction_quantity;
+            // after the counterparty's remainder is reserved again
+            self.notify_account(counterparty_id);
+            if aggressor_id != counterparty_id {
+                self.notify_account(aggressor_id);
+            }
+            trades.push(trade);
+            if order.quantity == 0 {
+                let view = order.view();
+                self.order_history.push(order);
+                return (trades, view);
             }
         };
-        if let Some(order) = order {
-            self.order_book.insert_order(order);
+        if order.can_rest() {
+            // an iceberg that traded on arrival rests showing a full slice
+            order.reveal();
+            let view = order.view();
+            self.rest(order);
+            return (trades, view);
         }
+        // market and immediate or cancel orders never rest
+        order.set_status(Status::Cancelled);
+        self.notify_cancelled(&order);
+        let view = order.view();
+        self.order_history.push(order);
+        (trades, view)
     }
 }
 
+/// Whether `order` would trade against `resting`, an order of its own account
+fn is_wash_trade(order: &OrderBase, resting: &OrderBase) -> bool {
+    resting.side == -order.side && order.crosses(resting)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::order::Side;
 
     use ordered_float::NotNan;
+
+    fn assert_balance_eq(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "balance {actual} != {expected}"
+        );
+    }
     #[test]
     fn process_orders_1() {
         let mut market = Market::default();
@@ -166,14 +886,14 @@ mod tests {
         let ask2_id = ask2.get_id();
         let ask4_id = ask4.get_id();
 
-        market.handle_incoming_order(ask1);
-        market.handle_incoming_order(ask2);
-        market.handle_incoming_order(ask3);
-        market.handle_incoming_order(ask4);
+        market.handle_incoming_order(ask1).unwrap();
+        market.handle_incoming_order(ask2).unwrap();
+        market.handle_incoming_order(ask3).unwrap();
+        market.handle_incoming_order(ask4).unwrap();
 
         let bid1 = OrderBase::build(21., 23, Side::Bid, bob_id).unwrap();
 
-        market.handle_incoming_order(bid1);
+        market.handle_incoming_order(bid1).unwrap();
 
         println!("{:#?}", market.order_book);
         let best_ask = market.order_book.pop(Side::Ask).unwrap();
@@ -218,21 +938,18 @@ mod tests {
         let ask3 = OrderBase::build(119.0, 38, Side::Ask, charlie_id).unwrap();
         // ask3 first consumes 17 of bid1 (clearing it). then consumes 1 of bid3 (clearing it). then consumes 19 of bid2.
 
-        let bid1_id = bid1.get_id();
         let bid2_id = bid2.get_id();
-        // I expected bid2 timestamp to be > bid1 timestamp and so it would be the order remaining
-        // But sometimes it flips
 
-        market.handle_incoming_order(bid1);
-        market.handle_incoming_order(bid2);
-        market.handle_incoming_order(ask1);
-        market.handle_incoming_order(ask2);
-        market.handle_incoming_order(bid3);
-        market.handle_incoming_order(ask3);
+        market.handle_incoming_order(bid1).unwrap();
+        market.handle_incoming_order(bid2).unwrap();
+        market.handle_incoming_order(ask1).unwrap();
+        market.handle_incoming_order(ask2).unwrap();
+        market.handle_incoming_order(bid3).unwrap();
+        market.handle_incoming_order(ask3).unwrap();
 
         let best_bid = market.order_book.pop(Side::Bid).unwrap();
 
-        assert!(best_bid.get_id() == bid2_id || best_bid.get_id() == bid1_id);
+        assert_eq!(best_bid.get_id(), bid2_id);
         assert_eq!(best_bid.limit.into_inner(), 121.5);
         assert_eq!(best_bid.quantity, 1);
 
@@ -240,6 +957,29 @@ mod tests {
         assert!(market.order_book.is_empty(Side::Bid));
     }
     #[test]
+    fn arrival_sequence_priority() {
+        let mut market = Market::default();
+        let alice_id = market.new_account(1e5, 100).unwrap();
+        let bob_id = market.new_account(1e5, 100).unwrap();
+
+        // built first but submitted second, so it queues behind ask2
+        let ask1 = OrderBase::build(10., 5, Side::Ask, alice_id).unwrap();
+        let ask2 = OrderBase::build(10., 5, Side::Ask, alice_id).unwrap();
+        let (ask1_id, ask2_id) = (ask1.get_id(), ask2.get_id());
+        market.handle_incoming_order(ask2).unwrap();
+        market.handle_incoming_order(ask1).unwrap();
+
+        let ask2 = market.order_book.peek(Side::Ask).unwrap();
+        assert_eq!(ask2.get_id(), ask2_id);
+        assert!(ask2.get_sequence() < market.find_order(ask1_id).unwrap().get_sequence());
+
+        let bid = OrderBase::build(10., 7, Side::Bid, bob_id).unwrap();
+        let trades = market.handle_incoming_order(bid).unwrap();
+        let passive: Vec<_> = trades.iter().map(|trade| trade.passive_order_id).collect();
+        assert_eq!(passive, vec![ask2_id, ask1_id]);
+        assert_eq!(market.order_book.peek(Side::Ask).unwrap().quantity, 3);
+    }
+    #[test]
     fn process_orders_3() {
         let mut market = Market::default();
 
@@ -258,36 +998,56 @@ mod tests {
 
         // Alice sets up the following:
         // - 30 @ 60.01 bid / 12 @ 60.11 ask
-        market.handle_incoming_order(OrderBase::build(60.01, 30, Side::Bid, alice_id).unwrap());
-        market.handle_incoming_order(OrderBase::build(60.11, 12, Side::Ask, alice_id).unwrap());
+        market
+            .handle_incoming_order(OrderBase::build(60.01, 30, Side::Bid, alice_id).unwrap())
+            .unwrap();
+        market
+            .handle_incoming_order(OrderBase::build(60.11, 12, Side::Ask, alice_id).unwrap())
+            .unwrap();
 
         // Bob sets up the following:
         // - 100 @ 60.08 bid / 10 @ 60.20 ask
-        market.handle_incoming_order(OrderBase::build(60.08, 100, Side::Bid, bob_id).unwrap());
-        market.handle_incoming_order(OrderBase::build(60.20, 10, Side::Ask, bob_id).unwrap());
+        market
+            .handle_incoming_order(OrderBase::build(60.08, 100, Side::Bid, bob_id).unwrap())
+            .unwrap();
+        market
+            .handle_incoming_order(OrderBase::build(60.20, 10, Side::Ask, bob_id).unwrap())
+            .unwrap();
 
         // Alice sets up the following:
         // - 15 @ 60.02 bid / 14 @ 60.08 ask
-        market.handle_incoming_order(OrderBase::build(60.02, 15, Side::Bid, alice_id).unwrap());
-        market.handle_incoming_order(OrderBase::build(60.08, 14, Side::Ask, alice_id).unwrap());
+        market
+            .handle_incoming_order(OrderBase::build(60.02, 15, Side::Bid, alice_id).unwrap())
+            .unwrap();
+        market
+            .handle_incoming_order(OrderBase::build(60.08, 14, Side::Ask, alice_id).unwrap())
+            .unwrap();
 
         // Charlie sets up the following:
         // - 120 @ 60.01 ask
-        market.handle_incoming_order(OrderBase::build(60.01, 120, Side::Ask, charlie_id).unwrap());
+        market
+            .handle_incoming_order(OrderBase::build(60.01, 120, Side::Ask, charlie_id).unwrap())
+            .unwrap();
 
         // Dan sets up the following
         // - 20 @ 60.10 bid / 10 @ 60.3 ask
-        market.handle_incoming_order(OrderBase::build(60.11, 20, Side::Bid, dan_id).unwrap());
-        market.handle_incoming_order(OrderBase::build(60.3, 10, Side::Ask, dan_id).unwrap());
+        market
+            .handle_incoming_order(OrderBase::build(60.11, 20, Side::Bid, dan_id).unwrap())
+            .unwrap();
+        market
+            .handle_incoming_order(OrderBase::build(60.3, 10, Side::Ask, dan_id).unwrap())
+            .unwrap();
 
         // Alice sets up the following
         // - 8 @ 60.09 ask
-        market.handle_incoming_order(OrderBase::build(60.08, 8, Side::Ask, alice_id).unwrap());
+        market
+            .handle_incoming_order(OrderBase::build(60.08, 8, Side::Ask, alice_id).unwrap())
+            .unwrap();
 
-        let alice_account = market.accounts.get(&alice_id);
-        let bob_account = market.accounts.get(&bob_id);
-        let charlie_account = market.accounts.get(&charlie_id);
-        let dan_account = market.accounts.get(&dan_id);
+        let alice_account = market.account_view(&alice_id);
+        let bob_account = market.account_view(&bob_id);
+        let charlie_account = market.account_view(&charlie_id);
+        let dan_account = market.account_view(&dan_id);
 
         println!("{:#?}", &alice_account);
         println!("{:#?}", &bob_account);
@@ -313,13 +1073,1415 @@ mod tests {
         assert!(market.order_book.is_empty(Side::Ask));
         assert!(market.order_book.is_empty(Side::Bid));
 
-        assert_eq!(alice_account.view().account_balance, 100002.74);
-        assert_eq!(alice_account.view().position, 0);
-        assert_eq!(bob_account.view().account_balance, 93998.02);
-        assert_eq!(bob_account.view().position, 100);
-        assert_eq!(charlie_account.view().account_balance, 107201.2);
-        assert_eq!(charlie_account.view().position, 880);
-        assert_eq!(dan_account.view().account_balance, 98798.04);
-        assert_eq!(dan_account.view().position, 1020);
+        // every trade executes at the resting order's limit
+        // Alice: -14 @ 60.08, +15 @ 60.02, +19 @ 60.01, -12 @ 60.11, -8 @ 60.11
+        // Bob: +14 @ 60.08, +86 @ 60.08
+        // Charlie: -86 @ 60.08, -15 @ 60.02, -19 @ 60.01
+        // Dan: +12 @ 60.11, +8 @ 60.11
+        assert_balance_eq(alice_account.account_balance, 100002.83);
+        assert_eq!(alice_account.position, 0);
+        assert_balance_eq(bob_account.account_balance, 93992.0);
+        assert_eq!(bob_account.position, 100);
+        assert_balance_eq(charlie_account.account_balance, 107207.37);
+        assert_eq!(charlie_account.position, 880);
+        assert_balance_eq(dan_account.account_balance, 98797.8);
+        assert_eq!(dan_account.position, 1020);
+    }
+    #[test]
+    fn process_orders_price_improvement() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        market
+            .handle_incoming_order(OrderBase::build(15., 10, Side::Ask, alice_id).unwrap())
+            .unwrap();
+        // Bob bids 21 but the resting ask only asks for 15
+        let trades = market
+            .handle_incoming_order(OrderBase::build(21., 4, Side::Bid, bob_id).unwrap())
+            .unwrap();
+
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price.into_inner(), 15.);
+
+        let alice_account = market.account_view(&alice_id);
+        let bob_account = market.account_view(&bob_id);
+
+        assert_balance_eq(alice_account.account_balance, 100060.);
+        assert_eq!(alice_account.position, -4);
+        assert_balance_eq(bob_account.account_balance, 99940.);
+        assert_eq!(bob_account.position, 4);
+
+        // and an aggressive ask is filled at the resting bid
+        market
+            .handle_incoming_order(OrderBase::build(14., 6, Side::Bid, bob_id).unwrap())
+            .unwrap();
+        let trades = market
+            .handle_incoming_order(OrderBase::build(12., 6, Side::Ask, alice_id).unwrap())
+            .unwrap();
+
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price.into_inner(), 14.);
+    }
+    #[test]
+    fn process_orders_trades() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        let ask1 = OrderBase::build(20., 10, Side::Ask, alice_id).unwrap();
+        let ask2 = OrderBase::build(21., 10, Side::Ask, alice_id).unwrap();
+        let (ask1_id, ask2_id) = (ask1.get_id(), ask2.get_id());
+
+        assert!(market.handle_incoming_order(ask1).unwrap().is_empty());
+        assert!(market.handle_incoming_order(ask2).unwrap().is_empty());
+
+        let bid1 = OrderBase::build(21., 15, Side::Bid, bob_id).unwrap();
+        let bid1_id = bid1.get_id();
+        let trades = market.handle_incoming_order(bid1).unwrap();
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].passive_order_id, ask1_id);
+        assert_eq!(trades[0].quantity, 10);
+        assert_eq!(trades[1].passive_order_id, ask2_id);
+        assert_eq!(trades[1].quantity, 5);
+        for trade in trades.iter() {
+            assert_eq!(trade.aggressor_order_id, bid1_id);
+            assert_eq!(trade.aggressor_side, Side::Bid);
+            assert_eq!(trade.aggressor_account_id, bob_id);
+            assert_eq!(trade.passive_account_id, alice_id);
+        }
+        assert_eq!(market.trades().len(), 2);
+    }
+    #[test]
+    fn process_market_order() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        market
+            .handle_incoming_order(OrderBase::build(20., 10, Side::Ask, alice_id).unwrap())
+            .unwrap();
+        market
+            .handle_incoming_order(OrderBase::build(25., 10, Side::Ask, alice_id).unwrap())
+            .unwrap();
+
+        // Bob sweeps both asks and the unfilled 5 is cancelled rather than rested
+        let bid = OrderBase::build_market(25, Side::Bid, bob_id).unwrap();
+        let bid_id = bid.get_id();
+        market.validate_order(&bid, bob_id).unwrap();
+        let trades = market.handle_incoming_order(bid).unwrap();
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].price.into_inner(), 20.);
+        assert_eq!(trades[1].price.into_inner(), 25.);
+        assert!(market.order_book.is_empty(Side::Ask));
+        assert!(market.order_book.is_empty(Side::Bid));
+
+        let bid = market.find_order(bid_id).unwrap();
+        assert_eq!(bid.status, Status::Cancelled);
+        assert_eq!(bid.quantity, 5);
+
+        let bob_account = market.account_view(&bob_id);
+        assert_balance_eq(bob_account.account_balance, 1e5 - 450.);
+        assert_eq!(bob_account.position, 20);
+    }
+    #[test]
+    fn validate_market_order() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(300.).unwrap(), 0);
+
+        market
+            .handle_incoming_order(OrderBase::build(20., 10, Side::Ask, alice_id).unwrap())
+            .unwrap();
+        market
+            .handle_incoming_order(OrderBase::build(25., 10, Side::Ask, alice_id).unwrap())
+            .unwrap();
+
+        // 10 @ 20 + 4 @ 25 = 300
+        let bid = OrderBase::build_market(14, Side::Bid, bob_id).unwrap();
+        assert!(market.validate_order(&bid, bob_id).is_ok());
+        // 10 @ 20 + 5 @ 25 = 325
+        let bid = OrderBase::build_market(15, Side::Bid, bob_id).unwrap();
+        assert_eq!(
+            market.validate_order(&bid, bob_id),
+            Err("Insufficient account balance")
+        );
+    }
+    #[test]
+    fn process_time_in_force() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        market
+            .handle_incoming_order(OrderBase::build(20., 10, Side::Ask, alice_id).unwrap())
+            .unwrap();
+        market
+            .handle_incoming_order(OrderBase::build(22., 10, Side::Ask, alice_id).unwrap())
+            .unwrap();
+
+        // only 10 is available at or below 21 so the fill or kill order is killed untouched
+        let fok = OrderBase::build(21., 15, Side::Bid, bob_id)
+            .unwrap()
+            .with_time_in_force(TimeInForce::FillOrKill);
+        let fok_id = fok.get_id();
+        assert!(market.handle_incoming_order(fok).unwrap().is_empty());
+        assert_eq!(market.find_order(fok_id).unwrap().status, Status::Cancelled);
+        assert_eq!(market.account_view(&bob_id).position, 0);
+
+        // but it is filled in full at 22
+        let fok = OrderBase::build(22., 15, Side::Bid, bob_id)
+            .unwrap()
+            .with_time_in_force(TimeInForce::FillOrKill);
+        let fok_id = fok.get_id();
+        assert_eq!(market.handle_incoming_order(fok).unwrap().len(), 2);
+        assert_eq!(market.find_order(fok_id).unwrap().status, Status::Executed);
+
+        // immediate or cancel takes the remaining 5 and cancels the rest
+        let ioc = OrderBase::build(22., 8, Side::Bid, bob_id)
+            .unwrap()
+            .with_time_in_force(TimeInForce::ImmediateOrCancel);
+        let ioc_id = ioc.get_id();
+        assert_eq!(market.handle_incoming_order(ioc).unwrap().len(), 1);
+        let ioc = market.find_order(ioc_id).unwrap();
+        assert_eq!(ioc.status, Status::Cancelled);
+        assert_eq!(ioc.quantity, 3);
+        assert!(market.order_book.is_empty(Side::Bid));
+        assert_eq!(market.account_view(&bob_id).position, 20);
+    }
+    #[test]
+    fn expire_orders() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        let gtd = OrderBase::build(20., 10, Side::Bid, alice_id)
+            .unwrap()
+            .with_time_in_force(TimeInForce::GoodTillDate(100.));
+        let gtd_id = gtd.get_id();
+        assert_eq!(
+            market.validate_order(&gtd, alice_id),
+            Err("Expiry is in the past")
+        );
+        market.handle_incoming_order(gtd).unwrap();
+        market
+            .handle_incoming_order(OrderBase::build(19., 10, Side::Bid, alice_id).unwrap())
+            .unwrap();
+
+        assert_eq!(market.expire_orders(99.), 0);
+        assert_eq!(market.expire_orders(100.), 1);
+        assert_eq!(market.find_order(gtd_id).unwrap().status, Status::Expired);
+        assert_eq!(market.order_book.peek(Side::Bid).unwrap().limit.into_inner(), 19.);
+    }
+    #[test]
+    fn process_post_only() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        market
+            .handle_incoming_order(OrderBase::build(60.08, 10, Side::Ask, alice_id).unwrap())
+            .unwrap();
+
+        // a post only bid that does not cross rests as usual
+        let bid = OrderBase::build(60.07, 10, Side::Bid, bob_id)
+            .unwrap()
+            .with_post_only(PostOnly::Reject);
+        assert!(market.handle_incoming_order(bid).unwrap().is_empty());
+
+        // one that crosses is rejected outright
+        let bid = OrderBase::build(60.10, 10, Side::Bid, bob_id)
+            .unwrap()
+            .with_post_only(PostOnly::Reject);
+        let bid_id = bid.get_id();
+        assert_eq!(
+            market.handle_incoming_order(bid).unwrap_err(),
+            MarketError::PostOnlyWouldCross
+        );
+        assert!(market.find_order(bid_id).is_none());
+
+        // or moved one tick behind the best ask
+        let bid = OrderBase::build(60.10, 10, Side::Bid, bob_id)
+            .unwrap()
+            .with_post_only(PostOnly::Reprice);
+        let bid_id = bid.get_id();
+        assert!(market.handle_incoming_order(bid).unwrap().is_empty());
+        let bid = market.find_order(bid_id).unwrap();
+        assert_eq!(bid.limit.into_inner(), 60.07);
+        assert_eq!(bid.status, Status::Pending);
+
+        // and likewise for asks against the best bid
+        let ask = OrderBase::build(59., 10, Side::Ask, alice_id)
+            .unwrap()
+            .with_post_only(PostOnly::Reprice);
+        let ask_id = ask.get_id();
+        assert!(market.handle_incoming_order(ask).unwrap().is_empty());
+        assert_eq!(market.find_order(ask_id).unwrap().limit.into_inner(), 60.08);
+        assert_eq!(market.account_view(&bob_id).position, 0);
+
+        // market and immediate orders never rest so cannot be post only
+        let market_bid = OrderBase::build_market(10, Side::Bid, bob_id)
+            .unwrap()
+            .with_post_only(PostOnly::Reject);
+        assert!(market.validate_order(&market_bid, bob_id).is_err());
+        let ioc_bid = OrderBase::build(50., 10, Side::Bid, bob_id)
+            .unwrap()
+            .with_time_in_force(TimeInForce::ImmediateOrCancel)
+            .with_post_only(PostOnly::Reprice);
+        assert!(market.validate_order(&ioc_bid, bob_id).is_err());
+    }
+    #[test]
+    fn process_stop_orders() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let charlie_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let dan_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        for limit in [20., 21., 22.] {
+            market
+                .handle_incoming_order(OrderBase::build(limit, 5, Side::Ask, alice_id).unwrap())
+                .unwrap();
+        }
+
+        // Charlie buys 5 at market once anything trades at 21 or above
+        let stop_market = OrderBase::build_market(5, Side::Bid, charlie_id)
+            .unwrap()
+            .with_stop_price(21.)
+            .unwrap();
+        let stop_market_id = stop_market.get_id();
+        // Dan buys 5 up to 22 once anything trades at 22 or above
+        let stop_limit = OrderBase::build(22., 5, Side::Bid, dan_id)
+            .unwrap()
+            .with_stop_price(22.)
+            .unwrap();
+        let stop_limit_id = stop_limit.get_id();
+        // Alice sells 5 at market should anything trade at 19 or below
+        let stop_sell = OrderBase::build_market(5, Side::Ask, alice_id)
+            .unwrap()
+            .with_stop_price(19.)
+            .unwrap();
+        let stop_sell_id = stop_sell.get_id();
+
+        assert!(market
+            .handle_incoming_order(stop_market)
+            .unwrap()
+            .is_empty());
+        assert!(market.handle_incoming_order(stop_limit).unwrap().is_empty());
+        assert!(market.handle_incoming_order(stop_sell).unwrap().is_empty());
+        assert_eq!(
+            market.find_order(stop_market_id).unwrap().status,
+            Status::Pending
+        );
+        assert_eq!(market.get_orders_by_account(alice_id).count(), 4);
+
+        // trading at 20 triggers nothing
+        let trades = market
+            .handle_incoming_order(OrderBase::build(20., 5, Side::Bid, bob_id).unwrap())
+            .unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(market.last_price().unwrap().into_inner(), 20.);
+
+        // trading at 21 releases Charlie's stop, whose trades at 22 release Dan's
+        let trades = market
+            .handle_incoming_order(OrderBase::build(21., 1, Side::Bid, bob_id).unwrap())
+            .unwrap();
+        let executions: Vec<(f64, usize)> = trades
+            .iter()
+            .map(|trade| (trade.price.into_inner(), trade.quantity))
+            .collect();
+        assert_eq!(executions, vec![(21., 1), (21., 4), (22., 1), (22., 4)]);
+        assert_eq!(trades[1].aggressor_order_id, stop_market_id);
+        assert_eq!(trades[3].aggressor_order_id, stop_limit_id);
+
+        assert_eq!(
+            market.find_order(stop_market_id).unwrap().status,
+            Status::Executed
+        );
+        // the rest of Dan's stop limit order rests in the book
+        let stop_limit = market.order_book.peek(Side::Bid).unwrap();
+        assert_eq!(stop_limit.get_id(), stop_limit_id);
+        assert_eq!(stop_limit.status, Status::PartiallyFilled);
+        assert_eq!(stop_limit.quantity, 1);
+        assert!(market.order_book.is_empty(Side::Ask));
+
+        assert_eq!(market.account_view(&charlie_id).position, 5);
+        assert_eq!(market.account_view(&dan_id).position, 4);
+
+        // untriggered stops can be cancelled
+        market.delete_order_by_id(stop_sell_id, alice_id).unwrap();
+        assert_eq!(
+            market.find_order(stop_sell_id).unwrap().status,
+            Status::Cancelled
+        );
+    }
+    #[test]
+    fn stop_order_time_priority() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let charlie_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let dan_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        // Dan's stop limit arrives before Bob's bid at the same limit
+        let stop_limit = OrderBase::build(20., 5, Side::Bid, dan_id)
+            .unwrap()
+            .with_stop_price(21.)
+            .unwrap();
+        let stop_limit_id = stop_limit.get_id();
+        market.handle_incoming_order(stop_limit).unwrap();
+        let bid = OrderBase::build(20., 5, Side::Bid, bob_id).unwrap();
+        let bid_id = bid.get_id();
+        market.handle_incoming_order(bid).unwrap();
+
+        // trading at 21 releases the stop onto Bob's level
+        market
+            .handle_incoming_order(OrderBase::build(21., 1, Side::Ask, alice_id).unwrap())
+            .unwrap();
+        market
+            .handle_incoming_order(OrderBase::build(21., 1, Side::Bid, charlie_id).unwrap())
+            .unwrap();
+        assert_eq!(
+            market.find_order(stop_limit_id).unwrap().status,
+            Status::Triggered
+        );
+
+        // but only queues behind him from the time it was released
+        let trades = market
+            .handle_incoming_order(OrderBase::build(20., 6, Side::Ask, alice_id).unwrap())
+            .unwrap();
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].passive_order_id, bid_id);
+        assert_eq!(trades[0].quantity, 5);
+        assert_eq!(trades[1].passive_order_id, stop_limit_id);
+        assert_eq!(trades[1].quantity, 1);
+    }
+    #[test]
+    fn stop_order_wash_trades() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let charlie_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        market
+            .submit_order(OrderBase::build(20., 5, Side::Ask, alice_id).unwrap())
+            .unwrap();
+        let stop_limit = OrderBase::build(22., 5, Side::Bid, bob_id)
+            .unwrap()
+            .with_stop_price(20.)
+            .unwrap();
+        let stop_limit_id = stop_limit.get_id();
+        market.submit_order(stop_limit).unwrap();
+
+        // an ask the pending stop would trade against is a wash trade
+        let ask = OrderBase::build(21., 5, Side::Ask, bob_id).unwrap();
+        assert_eq!(
+            market.validate_order(&ask, bob_id),
+            Err("Wash trades are not allowed")
+        );
+        let ask = OrderBase::build(23., 5, Side::Ask, bob_id).unwrap();
+        assert!(market.validate_order(&ask, bob_id).is_ok());
+
+        // a stop is cancelled rather than trade against its own account when released
+        market
+            .handle_incoming_order(OrderBase::build(21.5, 5, Side::Ask, bob_id).unwrap())
+            .unwrap();
+        let (trades, _) = market
+            .submit_order(OrderBase::build(20., 5, Side::Bid, charlie_id).unwrap())
+            .unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(
+            market.find_order(stop_limit_id).unwrap().status,
+            Status::Cancelled
+        );
+        assert_eq!(market.account_view(&bob_id).position, 0);
+
+        // pending stops count towards the cap on outstanding orders
+        for _ in 0..10 {
+            let stop = OrderBase::build(10., 1, Side::Bid, charlie_id)
+                .unwrap()
+                .with_stop_price(30.)
+                .unwrap();
+            market.submit_order(stop).unwrap();
+        }
+        let stop = OrderBase::build(10., 1, Side::Bid, charlie_id)
+            .unwrap()
+            .with_stop_price(30.)
+            .unwrap();
+        assert_eq!(
+            market.validate_order(&stop, charlie_id),
+            Err("Exceeds maximum number of outstanding orders allowed")
+        );
+    }
+    #[test]
+    fn process_iceberg_order() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let charlie_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        // Alice sells 25 showing 10 at a time, Bob sells 5 behind her
+        let iceberg = OrderBase::build(20., 25, Side::Ask, alice_id)
+            .unwrap()
+            .with_peak(10)
+            .unwrap();
+        let iceberg_id = iceberg.get_id();
+        let ask = OrderBase::build(20., 5, Side::Ask, bob_id).unwrap();
+        let ask_id = ask.get_id();
+        market.handle_incoming_order(iceberg).unwrap();
+        market.handle_incoming_order(ask).unwrap();
+
+        let (best_ask, _) = market.quote();
+        assert_eq!(best_ask.unwrap().get_id(), iceberg_id);
+        assert_eq!(best_ask.unwrap().public_view().quantity, 10);
+        assert_eq!(best_ask.unwrap().view().quantity, 25);
+
+        // consuming the first slice sends the iceberg behind Bob
+        let trades = market
+            .handle_incoming_order(OrderBase::build(20., 12, Side::Bid, charlie_id).unwrap())
+            .unwrap();
+        let executions: Vec<(Uuid, usize)> = trades
+            .iter()
+            .map(|trade| (trade.passive_order_id, trade.quantity))
+            .collect();
+        assert_eq!(executions, vec![(iceberg_id, 10), (ask_id, 2)]);
+
+        let (best_ask, _) = market.quote();
+        assert_eq!(best_ask.unwrap().get_id(), ask_id);
+        // only what is left on show is public, not who placed it or what has filled
+        let public_view = serde_json::to_value(best_ask.unwrap().public_view()).unwrap();
+        assert_eq!(public_view["quantity"], 3);
+        for private in ["account_id", "filled_quantity", "sequence", "arrival"] {
+            assert!(public_view.get(private).is_none());
+        }
+
+        // the reserve keeps being shown until it runs out
+        let trades = market
+            .handle_incoming_order(OrderBase::build(20., 20, Side::Bid, charlie_id).unwrap())
+            .unwrap();
+        let executions: Vec<(Uuid, usize)> = trades
+            .iter()
+            .map(|trade| (trade.passive_order_id, trade.quantity))
+            .collect();
+        assert_eq!(
+            executions,
+            vec![(ask_id, 3), (iceberg_id, 10), (iceberg_id, 5)]
+        );
+        assert_eq!(
+            market.find_order(iceberg_id).unwrap().status,
+            Status::Executed
+        );
+        assert!(market.order_book.is_empty(Side::Ask));
+        assert_eq!(market.order_book.peek(Side::Bid).unwrap().quantity, 2);
+    }
+    #[test]
+    fn amend_order() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e3).unwrap(), 0);
+
+        let ask1 = OrderBase::build(20., 10, Side::Ask, alice_id).unwrap();
+        let ask2 = OrderBase::build(20., 10, Side::Ask, alice_id).unwrap();
+        let (ask1_id, ask2_id) = (ask1.get_id(), ask2.get_id());
+        market.handle_incoming_order(ask1).unwrap();
+        market.handle_incoming_order(ask2).unwrap();
+
+        // reducing quantity keeps time priority
+        market
+            .amend_order(ask1_id, alice_id, None, Some(5))
+            .unwrap();
+        let best_ask = market.order_book.peek(Side::Ask).unwrap();
+        assert_eq!(best_ask.get_id(), ask1_id);
+        assert_eq!(best_ask.quantity, 5);
+
+        // increasing it does not
+        market
+            .amend_order(ask1_id, alice_id, None, Some(8))
+            .unwrap();
+        assert_eq!(market.order_book.peek(Side::Ask).unwrap().get_id(), ask2_id);
+
+        // an improved price takes priority again
+        market
+            .amend_order(ask1_id, alice_id, Some(19.), None)
+            .unwrap();
+        assert_eq!(market.order_book.peek(Side::Ask).unwrap().get_id(), ask1_id);
+
+        // and a price that crosses the book trades straight away
+        let bid = OrderBase::build(18., 5, Side::Bid, bob_id).unwrap();
+        let bid_id = bid.get_id();
+        market.handle_incoming_order(bid).unwrap();
+        let (trades, bid) = market.amend_order(bid_id, bob_id, Some(19.), None).unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price.into_inner(), 19.);
+        assert_eq!(trades[0].quantity, 5);
+        assert_eq!(bid.status, Status::Executed);
+        assert_eq!(market.find_order(bid_id).unwrap().status, Status::Executed);
+        assert_eq!(market.order_book.peek(Side::Ask).unwrap().quantity, 3);
+
+        // an amendment that fails validation leaves the order untouched
+        let bid = OrderBase::build(18., 5, Side::Bid, bob_id).unwrap();
+        let bid_id = bid.get_id();
+        market.handle_incoming_order(bid).unwrap();
+        assert_eq!(
+            market
+                .amend_order(bid_id, bob_id, None, Some(500))
+                .unwrap_err(),
+            MarketError::OrderInvalid("Insufficient account balance")
+        );
+        let best_bid = market.order_book.peek(Side::Bid).unwrap();
+        assert_eq!(best_bid.get_id(), bid_id);
+        assert_eq!(best_bid.quantity, 5);
+
+        assert_eq!(
+            market
+                .amend_order(bid_id, alice_id, None, Some(1))
+                .unwrap_err(),
+            MarketError::OrderNotOwned
+        );
+        assert_eq!(
+            market
+                .amend_order(bid_id, bob_id, None, Some(0))
+                .unwrap_err(),
+            MarketError::OrderInvalid("Quantity must be positive")
+        );
+        assert_eq!(
+            market
+                .amend_order(Uuid::new_v4(), bob_id, None, Some(1))
+                .unwrap_err(),
+            MarketError::OrderDoesNotExist
+        );
+    }
+    #[test]
+    fn order_view_lifecycle() {
+        let mut market = Market::default();
+        let alice_id = market.new_account(1e5, 100).unwrap();
+        let bob_id = market.new_account(1e5, 100).unwrap();
+
+        let ask1 = OrderBase::build(10., 10, Side::Ask, alice_id).unwrap();
+        let ask2 = OrderBase::build(11., 5, Side::Ask, alice_id).unwrap();
+        let (ask1_id, ask2_id) = (ask1.get_id(), ask2.get_id());
+        market.handle_incoming_order(ask1).unwrap();
+        market.handle_incoming_order(ask2).unwrap();
+
+        let view = market.find_order(ask2_id).unwrap().view();
+        // the timestamp is a wall clock time, not the limit price
+        assert!(view.timestamp > 1e9);
+        assert_eq!(view.original_quantity, 5);
+        assert_eq!(view.filled_quantity, 0);
+        assert_eq!(view.average_fill_price, None);
+
+        let bid = OrderBase::build(11., 12, Side::Bid, bob_id).unwrap();
+        let bid_id = bid.get_id();
+        market.handle_incoming_order(bid).unwrap();
+
+        let view = market.find_order(bid_id).unwrap().view();
+        assert_eq!(view.status, Status::Executed);
+        assert_eq!(view.original_quantity, 12);
+        assert_eq!(view.filled_quantity, 12);
+        assert_eq!(view.remaining_quantity, 0);
+        assert_balance_eq(
+            view.average_fill_price.unwrap(),
+            (10. * 10. + 2. * 11.) / 12.,
+        );
+        let fills: Vec<_> = market
+            .find_order(bid_id)
+            .unwrap()
+            .fills()
+            .iter()
+            .map(|fill| {
+                (
+                    fill.price.into_inner(),
+                    fill.quantity,
+                    fill.counter_order_id,
+                )
+            })
+            .collect();
+        assert_eq!(fills, vec![(10., 10, ask1_id), (11., 2, ask2_id)]);
+        assert_eq!(
+            market.find_order(ask1_id).unwrap().fills()[0].counter_order_id,
+            bid_id
+        );
+
+        let view = market.find_order(ask2_id).unwrap().view();
+        assert_eq!(view.status, Status::PartiallyFilled);
+        assert_eq!(view.original_quantity, 5);
+        assert_eq!(view.filled_quantity, 2);
+        assert_eq!(view.remaining_quantity, 3);
+        assert_eq!(view.average_fill_price, Some(11.));
+        assert!(view.updated_at >= view.timestamp);
+
+        // shrinking an order changes what is left but not what it was placed for or has filled
+        market
+            .amend_order(ask2_id, alice_id, None, Some(1))
+            .unwrap();
+        let view = market.find_order(ask2_id).unwrap().view();
+        assert_eq!(view.original_quantity, 5);
+        assert_eq!(view.filled_quantity, 2);
+        assert_eq!(view.remaining_quantity, 1);
+    }
+    #[test]
+    fn order_history() {
+        let mut market = Market::default();
+        let alice_id = market.new_account(1e5, 100).unwrap();
+        let ioc = |side| {
+            OrderBase::build(10., 1, side, alice_id)
+                .unwrap()
+                .with_time_in_force(TimeInForce::ImmediateOrCancel)
+        };
+
+        // completed orders are kept well beyond the old 64 order ring
+        let first = ioc(Side::Bid);
+        let first_id = first.get_id();
+        market.handle_incoming_order(first).unwrap();
+        for i in 0..99 {
+            let side = if i % 2 == 0 { Side::Ask } else { Side::Bid };
+            market.handle_incoming_order(ioc(side)).unwrap();
+        }
+        let first = market.get_order_by_id(first_id, alice_id).unwrap();
+        assert_eq!(first.status, Status::Cancelled);
+        let resting = OrderBase::build(20., 1, Side::Ask, alice_id).unwrap();
+        market.handle_incoming_order(resting).unwrap();
+
+        // filters apply to live and completed orders alike
+        let all = OrderFilter::default();
+        assert_eq!(market.query_orders(alice_id, &all, None, 1000).len(), 101);
+        let asks = OrderFilter {
+            side: Some(Side::Ask),
+            ..Default::default()
+        };
+        assert_eq!(market.query_orders(alice_id, &asks, None, 1000).len(), 51);
+        let pending = OrderFilter {
+            status: Some(Status::Pending),
+            ..Default::default()
+        };
+        let pending = market.query_orders(alice_id, &pending, None, 1000);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].limit.into_inner(), 20.);
+        let future = OrderFilter {
+            from: Some(f64::MAX),
+            ..Default::default()
+        };
+        assert!(market
+            .query_orders(alice_id, &future, None, 1000)
+            .is_empty());
+
+        // paging with the last arrival as the cursor visits every order once, in arrival order
+        let mut cursor = None;
+        let mut arrivals = Vec::new();
+        loop {
+            let page = market.query_orders(alice_id, &all, cursor, 30);
+            arrivals.extend(page.iter().map(|order| order.get_arrival()));
+            match page.last() {
+                Some(order) if page.len() == 30 => cursor = Some(order.get_arrival()),
+                _ => break,
+            }
+        }
+        assert_eq!(arrivals, (1..=101).collect::<Vec<u64>>());
+    }
+    #[test]
+    fn order_history_retention() {
+        let mut market = Market::with_config(MarketConfig {
+            order_history: Retention {
+                max_orders: Some(3),
+                max_age: Some(60.),
+            },
+            ..Default::default()
+        });
+        let alice_id = market.new_account(1e5, 100).unwrap();
+
+        let mut order_ids = Vec::new();
+        for _ in 0..5 {
+            let order = OrderBase::build(10., 1, Side::Bid, alice_id)
+                .unwrap()
+                .with_time_in_force(TimeInForce::ImmediateOrCancel);
+            order_ids.push(order.get_id());
+            market.handle_incoming_order(order).unwrap();
+        }
+        // only the three most recently completed orders are kept
+        for order_id in &order_ids[..2] {
+            assert_eq!(
+                market.get_order_by_id(*order_id, alice_id).unwrap_err(),
+                MarketError::OrderDoesNotExist
+            );
+        }
+        for order_id in &order_ids[2..] {
+            assert!(market.get_order_by_id(*order_id, alice_id).is_ok());
+        }
+
+        // and none of them outlive the maximum age
+        let updated_at = market.find_order(order_ids[4]).unwrap().view().updated_at;
+        market.expire_orders(updated_at + 30.);
+        assert_eq!(market.order_history.len(), 3);
+        market.expire_orders(updated_at + 61.);
+        assert!(market.order_history.is_empty());
+        assert_eq!(market.get_orders_by_account(alice_id).count(), 0);
+
+        // a submitted order is still reported as it ended up when it is not kept at all
+        let mut market = Market::with_config(MarketConfig {
+            order_history: Retention {
+                max_orders: Some(0),
+                max_age: None,
+            },
+            ..Default::default()
+        });
+        let alice_id = market.new_account(1e5, 100).unwrap();
+        let order = OrderBase::build(10., 1, Side::Bid, alice_id)
+            .unwrap()
+            .with_time_in_force(TimeInForce::ImmediateOrCancel);
+        let order_id = order.get_id();
+        let (_, order) = market.submit_order(order).unwrap();
+        assert_eq!(order.status, Status::Cancelled);
+        assert!(market.get_order_by_id(order_id, alice_id).is_err());
+    }
+    #[test]
+    fn bars_limit() {
+        let mut market = Market::default();
+        let alice_id = market.new_account(1e5, 100).unwrap();
+        let bid = OrderBase::build(10., 1, Side::Bid, alice_id).unwrap();
+        let ask = OrderBase::build(10., 1, Side::Ask, alice_id).unwrap();
+        for timestamp in [0., 60., 120., 180.] {
+            let mut trade = Trade::new(&bid, &ask, NotNan::new(10.).unwrap(), 1);
+            trade.timestamp = timestamp;
+            market.bars.update(&trade);
+        }
+
+        let starts = |from, to| -> Vec<f64> {
+            market
+                .bars(Interval::OneMinute, from, to, 2)
+                .iter()
+                .map(|bar| bar.start)
+                .collect()
+        };
+        // the first bars from `from`, otherwise the latest
+        assert_eq!(starts(Some(60.), None), vec![60., 120.]);
+        assert_eq!(starts(None, None), vec![120., 180.]);
+        assert_eq!(starts(None, Some(180.)), vec![60., 120.]);
+    }
+    #[test]
+    fn trade_tape() {
+        let mut market = Market::default();
+        let alice_id = market.new_account(1e5, 100).unwrap();
+        let bob_id = market.new_account(1e5, 100).unwrap();
+
+        assert!(market.trades_since(None, 10).unwrap().is_empty());
+        for price in [10., 11., 12., 13., 14.] {
+            let ask = OrderBase::build(price, 1, Side::Ask, alice_id).unwrap();
+            market.handle_incoming_order(ask).unwrap();
+            let bid = OrderBase::build_market(1, Side::Bid, bob_id).unwrap();
+            market.handle_incoming_order(bid).unwrap();
+        }
+        let prices = |trades: &[Trade]| -> Vec<f64> {
+            trades
+                .iter()
+                .map(|trade| trade.price.into_inner())
+                .collect()
+        };
+
+        // the latest trades, oldest first
+        assert_eq!(
+            prices(market.trades_since(None, 2).unwrap()),
+            vec![13., 14.]
+        );
+        assert_eq!(market.trades_since(None, 100).unwrap().len(), 5);
+
+        // polling from a known trade picks up where it left off
+        let since = market.trades()[1].get_id();
+        assert_eq!(
+            prices(market.trades_since(Some(since), 2).unwrap()),
+            vec![12., 13.]
+        );
+        let since = market.trades()[4].get_id();
+        assert!(market.trades_since(Some(since), 2).unwrap().is_empty());
+        assert_eq!(
+            market.trades_since(Some(Uuid::new_v4()), 2).unwrap_err(),
+            MarketError::TradeDoesNotExist
+        );
+    }
+    #[test]
+    fn trade_log_max_trades() {
+        let mut market = Market::with_config(MarketConfig {
+            max_trades: Some(3),
+            ..Default::default()
+        });
+        let alice_id = market.new_account(1e5, 100).unwrap();
+        let bob_id = market.new_account(1e5, 100).unwrap();
+
+        let mut trade_ids = Vec::new();
+        for price in [10., 11., 12., 13., 14., 15., 16., 17.] {
+            let ask = OrderBase::build(price, 1, Side::Ask, alice_id).unwrap();
+            market.handle_incoming_order(ask).unwrap();
+            let bid = OrderBase::build_market(1, Side::Bid, bob_id).unwrap();
+            let trades = market.handle_incoming_order(bid).unwrap();
+            trade_ids.push(trades[0].get_id());
+        }
+        let prices: Vec<f64> = market
+            .trades()
+            .iter()
+            .map(|trade| trade.price.into_inner())
+            .collect();
+        assert_eq!(prices, vec![15., 16., 17.]);
+        assert_eq!(market.trades_since(None, 10).unwrap().len(), 3);
+
+        // dropped trades can no longer be polled from
+        assert_eq!(
+            market.trades_since(Some(trade_ids[4]), 2).unwrap_err(),
+            MarketError::TradeDoesNotExist
+        );
+        let trades = market.trades_since(Some(trade_ids[5]), 1).unwrap();
+        assert_eq!(trades[0].get_id(), trade_ids[6]);
+    }
+    #[test]
+    fn market_feed() {
+        let mut market = Market::default();
+        let alice_id = market.new_account(1e5, 100).unwrap();
+        let bob_id = market.new_account(1e5, 100).unwrap();
+        let mut feed = market.subscribe();
+        let mut drain =
+            || -> Vec<MarketUpdate> { std::iter::from_fn(|| feed.try_recv().ok()).collect() };
+        let level = |price, quantity, order_count| PriceLevel {
+            price,
+            quantity,
+            order_count,
+        };
+
+        let ask = OrderBase::build(10., 5, Side::Ask, alice_id).unwrap();
+        let ask_id = ask.get_id();
+        market.handle_incoming_order(ask).unwrap();
+        let bid = OrderBase::build(10., 2, Side::Bid, bob_id).unwrap();
+        let trades = market.handle_incoming_order(bid).unwrap();
+        let bid = OrderBase::build(9., 1, Side::Bid, bob_id).unwrap();
+        market.handle_incoming_order(bid).unwrap();
+        // a bid below the best bid changes the depth but not the quote
+        let bid = OrderBase::build(8., 1, Side::Bid, bob_id).unwrap();
+        market.handle_incoming_order(bid).unwrap();
+        market.delete_order_by_id(ask_id, alice_id).unwrap();
+
+        let updates = drain();
+        let sequences: Vec<u64> = updates.iter().map(|update| update.sequence).collect();
+        assert_eq!(sequences, (1..=10).collect::<Vec<u64>>());
+        assert_eq!(market.feed_sequence(), 10);
+        let events: Vec<MarketEvent> = updates.into_iter().map(|update| update.event).collect();
+        assert_eq!(
+            events,
+            vec![
+                MarketEvent::Depth {
+                    side: Side::Ask,
+                    level: level(10., 5, 1),
+                },
+                MarketEvent::Quote {
+                    ask: Some(level(10., 5, 1)),
+                    bid: None,
+                },
+                MarketEvent::Trade(trades[0].public_view()),
+                MarketEvent::Depth {
+                    side: Side::Ask,
+                    level: level(10., 3, 1),
+                },
+                MarketEvent::Quote {
+                    ask: Some(level(10., 3, 1)),
+                    bid: None,
+                },
+                MarketEvent::Depth {
+                    side: Side::Bid,
+                    level: level(9., 1, 1),
+                },
+                MarketEvent::Quote {
+                    ask: Some(level(10., 3, 1)),
+                    bid: Some(level(9., 1, 1)),
+                },
+                MarketEvent::Depth {
+                    side: Side::Bid,
+                    level: level(8., 1, 1),
+                },
+                MarketEvent::Depth {
+                    side: Side::Ask,
+                    level: level(10., 0, 0),
+                },
+                MarketEvent::Quote {
+                    ask: None,
+                    bid: Some(level(9., 1, 1)),
+                },
+            ]
+        );
+    }
+    #[test]
+    fn account_feed() {
+        let mut market = Market::default();
+        let alice_id = market.new_account(1e5, 100).unwrap();
+        let bob_id = market.new_account(1e5, 100).unwrap();
+        let mut alice_feed = market.subscribe_account(alice_id);
+        let mut bob_feed = market.subscribe_account(bob_id);
+        let events = |feed: &mut broadcast::Receiver<AccountUpdate>| -> Vec<AccountEvent> {
+            let updates: Vec<AccountUpdate> = std::iter::from_fn(|| feed.try_recv().ok()).collect();
+            let sequences: Vec<u64> = updates.iter().map(|update| update.sequence).collect();
+            assert!(sequences.windows(2).all(|pair| pair[1] == pair[0] + 1));
+            updates.into_iter().map(|update| update.event).collect()
+        };
+
+        let ask = OrderBase::build(10., 5, Side::Ask, alice_id).unwrap();
+        let ask_id = ask.get_id();
+        market.submit_order(ask).unwrap();
+        let bid = OrderBase::build(10., 2, Side::Bid, bob_id).unwrap();
+        let bid_id = bid.get_id();
+        market.submit_order(bid).unwrap();
+
+        let alice_events = events(&mut alice_feed);
+        assert_eq!(alice_events.len(), 4);
+        assert!(
+            matches!(&alice_events[0], AccountEvent::Accepted { order } if order.quantity == 5)
+        );
+        // resting the ask reserves Alice's position
+        assert!(matches!(
+            &alice_events[1],
+            AccountEvent::Account { account } if account.reserved_position == 5
+        ));
+        match &alice_events[2] {
+            AccountEvent::Fill {
+                order_id,
+                fill,
+                status,
+                remaining_quantity,
+            } => {
+                assert_eq!(*order_id, ask_id.to_string());
+                assert_eq!(fill.counter_order_id, bid_id.to_string());
+                assert_eq!((fill.price, fill.quantity), (10., 2));
+                assert_eq!(*status, Status::PartiallyFilled);
+                assert_eq!(*remaining_quantity, 3);
+            }
+            event => panic!("expected a fill, got {event:?}"),
+        }
+        assert_eq!(
+            alice_events[3],
+            AccountEvent::Account {
+                account: market.account_view(&alice_id)
+            }
+        );
+
+        let bob_events = events(&mut bob_feed);
+        assert_eq!(bob_events.len(), 3);
+        assert!(matches!(
+            &bob_events[1],
+            AccountEvent::Fill {
+                status: Status::Executed,
+                remaining_quantity: 0,
+                ..
+            }
+        ));
+        assert!(
+            matches!(&bob_events[2], AccountEvent::Account { account } if account.position == 102)
+        );
+
+        // rejections and cancellations only reach the account concerned
+        let bid = OrderBase::build(10., 1, Side::Bid, bob_id)
+            .unwrap()
+            .with_post_only(PostOnly::Reject);
+        assert!(market.submit_order(bid).is_err());
+        let bid = OrderBase::build(9., 1_000_000, Side::Bid, bob_id).unwrap();
+        assert_eq!(
+            market.submit_order(bid).unwrap_err(),
+            MarketError::OrderInvalid("Insufficient account balance")
+        );
+        market.delete_order_by_id(ask_id, alice_id).unwrap();
+
+        let bob_events = events(&mut bob_feed);
+        assert_eq!(bob_events.len(), 2);
+        assert!(bob_events
+            .iter()
+            .all(|event| matches!(event, AccountEvent::Rejected { .. })));
+        assert!(matches!(
+            &bob_events[1],
+            AccountEvent::Rejected { reason, .. } if reason == "Insufficient account balance"
+        ));
+        let alice_events = events(&mut alice_feed);
+        assert!(matches!(
+            &alice_events[..],
+            [
+                AccountEvent::Account { account },
+                AccountEvent::Cancelled { order },
+            ] if account.reserved_position == 0 && order.status == Status::Cancelled
+        ));
+        assert_eq!(market.account_feed_sequence(alice_id), 6);
+
+        // once Alice's subscriber is gone the feed is dropped rather than published into
+        drop(alice_feed);
+        let ask = OrderBase::build(10., 1, Side::Ask, alice_id).unwrap();
+        market.submit_order(ask).unwrap();
+        assert_eq!(market.account_feed_sequence(alice_id), 0);
+        let mut alice_feed = market.subscribe_account(alice_id);
+        assert!(events(&mut alice_feed).is_empty());
+    }
+    #[test]
+    fn order_ownership() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        let bid = OrderBase::build(20., 10, Side::Bid, alice_id).unwrap();
+        let bid_id = bid.get_id();
+        market.handle_incoming_order(bid).unwrap();
+
+        assert_eq!(
+            market.get_order_by_id(bid_id, bob_id).unwrap_err(),
+            MarketError::OrderNotOwned
+        );
+        assert_eq!(
+            market.delete_order_by_id(bid_id, bob_id).unwrap_err(),
+            MarketError::OrderNotOwned
+        );
+        assert!(market.get_order_by_id(bid_id, alice_id).is_ok());
+
+        market.delete_order_by_id(bid_id, alice_id).unwrap();
+        assert_eq!(
+            market.delete_order_by_id(bid_id, alice_id).unwrap_err(),
+            MarketError::OrderCannotBeCancelled
+        );
+        assert_eq!(
+            market
+                .get_order_by_id(Uuid::new_v4(), alice_id)
+                .unwrap_err(),
+            MarketError::OrderDoesNotExist
+        );
+    }
+    #[test]
+    fn reserved_balance() {
+        let mut market = Market::default();
+
+        let alice_id = market
+            .accounts
+            .create_new_account(NotNan::new(1000.).unwrap(), 0);
+        let bob_id = market
+            .accounts
+            .create_new_account(NotNan::new(1e5).unwrap(), 20);
+
+        // the first bid holds back all of alice's cash, so the rest are refused
+        let bid1 = OrderBase::build(10., 100, Side::Bid, alice_id).unwrap();
+        let bid1_id = bid1.get_id();
+        market.submit_order(bid1).unwrap();
+        for _ in 0..9 {
+            let bid = OrderBase::build(10., 100, Side::Bid, alice_id).unwrap();
+            assert_eq!(
+                market.submit_order(bid).unwrap_err(),
+                MarketError::OrderInvalid("Insufficient account balance")
+            );
+        }
+        let alice = market.account_view(&alice_id);
+        assert_balance_eq(alice.reserved_balance, 1000.);
+        assert_balance_eq(alice.available_balance, 0.);
+
+        // a partial fill releases what was spent and keeps the remainder reserved
+        let ask = OrderBase::build(10., 40, Side::Ask, bob_id).unwrap();
+        market.submit_order(ask).unwrap();
+        let alice = market.account_view(&alice_id);
+        assert_balance_eq(alice.account_balance, 600.);
+        assert_balance_eq(alice.reserved_balance, 600.);
+        assert_balance_eq(alice.available_balance, 0.);
+
+        // resting asks hold back position
+        let ask = OrderBase::build(11., 15, Side::Ask, bob_id).unwrap();
+        let ask_id = ask.get_id();
+        market.submit_order(ask).unwrap();
+        let bob = market.account_view(&bob_id);
+        assert_eq!(bob.position, -20);
+        assert_eq!(bob.reserved_position, 15);
+        assert_eq!(bob.available_position, -35);
+
+        // amending and cancelling give the reservation back
+        market
+            .amend_order(bid1_id, alice_id, None, Some(10))
+            .unwrap();
+        assert_balance_eq(market.account_view(&alice_id).reserved_balance, 100.);
+        market.delete_order_by_id(bid1_id, alice_id).unwrap();
+        market.delete_order_by_id(ask_id, bob_id).unwrap();
+        let alice = market.account_view(&alice_id);
+        assert_balance_eq(alice.reserved_balance, 0.);
+        assert_balance_eq(alice.available_balance, 600.);
+        assert_eq!(market.account_view(&bob_id).reserved_position, 0);
+    }
+    #[test]
+    fn margin() {
+        let mut market = Market::with_config(MarketConfig {
+            margin: Margin {
+                initial: 0.5,
+                maintenance: 0.25,
+                max_leverage: None,
+            },
+            ..Default::default()
+        });
+        let alice_id = market.new_account(1000., 0).unwrap();
+        let bob_id = market.new_account(1e5, 1000).unwrap();
+        let charlie_id = market.new_account(1000., 0).unwrap();
+
+        let ask = OrderBase::build(10., 300, Side::Ask, bob_id).unwrap();
+        market.submit_order(ask).unwrap();
+        // alice may buy up to twice her cash
+        let bid = OrderBase::build(10., 201, Side::Bid, alice_id).unwrap();
+        assert_eq!(
+            market.submit_order(bid).unwrap_err(),
+            MarketError::OrderInvalid("Insufficient account balance")
+        );
+        let bid = OrderBase::build(10., 200, Side::Bid, alice_id).unwrap();
+        market.submit_order(bid).unwrap();
+        let alice = market.account_view(&alice_id);
+        assert_balance_eq(alice.account_balance, -1000.);
+        assert_eq!(alice.equity, Some(1000.));
+        assert_eq!(alice.initial_margin, Some(1000.));
+        assert_eq!(alice.maintenance_margin, Some(500.));
+
+        // charlie's short sale is marked at the last trade price of 10
+        let bid = OrderBase::build(9., 300, Side::Bid, bob_id).unwrap();
+        market.submit_order(bid).unwrap();
+        market.set_max_leverage(charlie_id, Some(1.)).unwrap();
+        let ask = OrderBase::build(9., 150, Side::Ask, charlie_id).unwrap();
+        assert_eq!(
+            market.submit_order(ask).unwrap_err(),
+            MarketError::OrderInvalid("Insufficient account balance")
+        );
+        market.set_max_leverage(charlie_id, None).unwrap();
+        let ask = OrderBase::build(9., 150, Side::Ask, charlie_id).unwrap();
+        market.submit_order(ask).unwrap();
+        let charlie = market.account_view(&charlie_id);
+        assert_eq!(charlie.position, -150);
+        assert_eq!(charlie.equity, Some(1000.));
+    }
+    #[test]
+    fn liquidation() {
+        let mut market = Market::with_config(MarketConfig {
+            margin: Margin {
+                initial: 0.5,
+                maintenance: 0.25,
+                max_leverage: None,
+            },
+            ..Default::default()
+        });
+        let alice_id = market.new_account(1000., 0).unwrap();
+        let bob_id = market.new_account(1e5, 1000).unwrap();
+        let charlie_id = market.new_account(1e5, 0).unwrap();
+        let dan_id = market.new_account(1e5, 100).unwrap();
+        let mut updates = market.subscribe_account(alice_id);
+
+        // alice buys 200 on 2x leverage and offers some of it back
+        let ask = OrderBase::build(10., 200, Side::Ask, bob_id).unwrap();
+        market.submit_order(ask).unwrap();
+        let bid = OrderBase::build(10., 200, Side::Bid, alice_id).unwrap();
+        market.submit_order(bid).unwrap();
+        let ask = OrderBase::build(20., 10, Side::Ask, alice_id).unwrap();
+        let ask_id = ask.get_id();
+        market.submit_order(ask).unwrap();
+        let bid = OrderBase::build(6., 500, Side::Bid, charlie_id).unwrap();
+        market.submit_order(bid).unwrap();
+        assert!(market.liquidations(&alice_id).is_empty());
+
+        // a trade at 6 leaves her 200 of equity against 300 of maintenance margin
+        let ask = OrderBase::build(6., 1, Side::Ask, dan_id).unwrap();
+        let (trades, _) = market.submit_order(ask).unwrap();
+        assert_eq!(trades.len(), 1);
+
+        let liquidations = market.liquidations(&alice_id);
+        assert_eq!(liquidations.len(), 1);
+        let liquidation = &liquidations[0];
+        assert_eq!(liquidation.mark_price.into_inner(), 6.);
+        assert_balance_eq(liquidation.equity, 200.);
+        assert_balance_eq(liquidation.maintenance_margin, 300.);
+        assert_eq!(liquidation.position, 200);
+        assert_eq!(liquidation.cancelled_orders, vec![ask_id]);
+
+        assert_eq!(market.find_order(ask_id).unwrap().status, Status::Cancelled);
+        let closing = market.find_order(liquidation.order_id).unwrap();
+        assert_eq!(closing.order_type, OrderType::Market);
+        assert_eq!(closing.status, Status::Executed);
+        let alice = market.account_view(&alice_id);
+        assert_eq!(alice.position, 0);
+        assert_balance_eq(alice.account_balance, 200.);
+        assert_eq!(market.account_view(&charlie_id).position, 201);
+        assert!(market.liquidations(&dan_id).is_empty());
+
+        let liquidation = liquidation.view();
+        let events: Vec<AccountEvent> = std::iter::from_fn(|| updates.try_recv().ok())
+            .map(|update| update.event)
+            .collect();
+        assert!(events.contains(&AccountEvent::Liquidation { liquidation }));
+    }
+    #[test]
+    fn liquidation_without_liquidity() {
+        let mut market = Market::with_config(MarketConfig {
+            margin: Margin {
+                initial: 0.5,
+                maintenance: 0.25,
+                max_leverage: None,
+            },
+            ..Default::default()
+        });
+        let alice_id = market.new_account(1000., 0).unwrap();
+        let bob_id = market.new_account(1e5, 1000).unwrap();
+        let charlie_id = market.new_account(1e5, 0).unwrap();
+
+        let ask = OrderBase::build(10., 200, Side::Ask, bob_id).unwrap();
+        market.submit_order(ask).unwrap();
+        let bid = OrderBase::build(10., 200, Side::Bid, alice_id).unwrap();
+        market.submit_order(bid).unwrap();
+        let ask = OrderBase::build(20., 50, Side::Ask, alice_id).unwrap();
+        let ask_id = ask.get_id();
+        market.submit_order(ask).unwrap();
+        let mut alice_feed = market.subscribe_account(alice_id);
+
+        // trading at 6 puts alice under margin with no bids to sell into, however often
+        for _ in 0..3 {
+            let ask = OrderBase::build(6., 1, Side::Ask, bob_id).unwrap();
+            market.submit_order(ask).unwrap();
+            let bid = OrderBase::build(6., 1, Side::Bid, charlie_id).unwrap();
+            market.submit_order(bid).unwrap();
+        }
+        assert!(market.liquidations(&alice_id).is_empty());
+        assert_eq!(market.account_view(&alice_id).position, 200);
+        // and Alice's resting ask is left alone rather than cancelled for a market order that
+        // closes nothing
+        assert_eq!(market.get_orders_by_account(alice_id).count(), 2);
+        assert_eq!(
+            market.get_order_by_id(ask_id, alice_id).unwrap().status,
+            Status::Pending
+        );
+        assert!(alice_feed.try_recv().is_err());
+
+        // until there is something to trade against
+        let bid = OrderBase::build(5., 200, Side::Bid, charlie_id).unwrap();
+        market.submit_order(bid).unwrap();
+        let ask = OrderBase::build(6., 1, Side::Ask, bob_id).unwrap();
+        market.submit_order(ask).unwrap();
+        let bid = OrderBase::build(6., 1, Side::Bid, charlie_id).unwrap();
+        market.submit_order(bid).unwrap();
+        assert_eq!(market.liquidations(&alice_id).len(), 1);
+        assert_eq!(market.liquidations(&alice_id)[0].cancelled_orders, [ask_id]);
+        assert_eq!(market.account_view(&alice_id).position, 0);
+    }
+    #[test]
+    fn mark_price() {
+        let mut market = Market::with_config(MarketConfig {
+            mark_price: MarkPrice::Mid,
+            ..Default::default()
+        });
+        let alice_id = market.new_account(1e5, 0).unwrap();
+        let bob_id = market.new_account(1e5, 100).unwrap();
+
+        let ask = OrderBase::build(10., 20, Side::Ask, bob_id).unwrap();
+        market.submit_order(ask).unwrap();
+        let bid = OrderBase::build(10., 10, Side::Bid, alice_id).unwrap();
+        market.submit_order(bid).unwrap();
+        // only the ask is left, so the last trade price is used
+        assert_eq!(market.account_view(&alice_id).unrealized_pnl, Some(0.));
+
+        let bid = OrderBase::build(8., 10, Side::Bid, alice_id).unwrap();
+        market.submit_order(bid).unwrap();
+        let alice = market.account_view(&alice_id);
+        assert_eq!(market.mark(), NotNan::new(9.).ok());
+        assert_eq!(alice.average_entry_price, Some(10.));
+        assert_eq!(alice.unrealized_pnl, Some(-10.));
+        assert_eq!(alice.equity, Some(1e5 - 10.));
+        assert_eq!(alice.trade_count, 1);
+    }
+    #[test]
+    fn fees() {
+        use crate::fees::Rate;
+
+        let mut market = Market::with_config(MarketConfig {
+            fees: FeeSchedule::flat(Rate::PerShare(-0.002), Rate::PerShare(0.003)),
+            ..Default::default()
+        });
+        let alice_id = market.new_account(1e5, 0).unwrap();
+        let bob_id = market.new_account(1e5, 100).unwrap();
+
+        let ask = OrderBase::build(10., 100, Side::Ask, bob_id).unwrap();
+        let ask_id = ask.get_id();
+        market.submit_order(ask).unwrap();
+        let bid = OrderBase::build(10., 40, Side::Bid, alice_id).unwrap();
+        let bid_id = bid.get_id();
+        let (trades, _) = market.submit_order(bid).unwrap();
+
+        assert_balance_eq(trades[0].aggressor_fee, 0.12);
+        assert_balance_eq(trades[0].passive_fee, -0.08);
+        assert_balance_eq(market.find_order(bid_id).unwrap().fills()[0].fee, 0.12);
+        assert_balance_eq(market.find_order(ask_id).unwrap().fills()[0].fee, -0.08);
+
+        let alice = market.account_view(&alice_id);
+        assert_balance_eq(alice.fees_paid, 0.12);
+        assert_balance_eq(alice.account_balance, 1e5 - 400. - 0.12);
+        let bob = market.account_view(&bob_id);
+        assert_balance_eq(bob.fees_paid, -0.08);
+        assert_balance_eq(bob.account_balance, 1e5 + 400. + 0.08);
     }
 }
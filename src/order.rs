@@ -1,46 +1,69 @@
-use std::cmp::Ordering;
-use std::collections::VecDeque;
+use std::cmp::min;
+use std::collections::{btree_map, hash_map, BTreeMap, HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::ops::Neg;
+use std::ops::{Bound, Neg};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use keyed_priority_queue::KeyedPriorityQueue;
 use ordered_float::NotNan;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::account::{Account, AccountId};
+use crate::account::AccountId;
+use crate::trade::Trade;
 
 // consider pub (super)
 
+/// Resting orders kept as price levels, each a FIFO queue of order ids in time priority.
 #[derive(Debug, Default)]
 pub struct OrderBook {
-    bids: KeyedPriorityQueue<Uuid, BidOrder>,
-    asks: KeyedPriorityQueue<Uuid, AskOrder>,
+    bids: BTreeMap<NotNan<f64>, Level>,
+    asks: BTreeMap<NotNan<f64>, Level>,
+    /// every resting order by id, whose side and limit locate its price level and whose key
+    /// locates it within the level
+    orders: HashMap<Uuid, (QueueKey, OrderBase)>,
+    // orders inserted so far, which breaks ties between equal sequence numbers
+    insertions: u64,
+    accounts: HashMap<AccountId, HashSet<Uuid>>,
+    // price levels touched since `take_changed_levels` was last called
+    changed: HashSet<(Side, NotNan<f64>)>,
+}
+
+// (sequence, insertion): orders queue by sequence number, then by when they were inserted
+type QueueKey = (u64, u64);
+
+/// The orders resting at one price, oldest first
+#[derive(Debug, Default)]
+struct Level {
+    order_ids: BTreeMap<QueueKey, Uuid>,
+    /// displayed quantity summed over the level
+    quantity: usize,
 }
 
 impl OrderBook {
     pub fn peek(&self, side: Side) -> Option<&OrderBase> {
-        match side {
-            Side::Ask => Some(&self.asks.peek()?.1.order),
-            Side::Bid => Some(&self.bids.peek()?.1.order),
-        }
+        let (_, order_id) = self.levels(side).next()?.1.order_ids.first_key_value()?;
+        self.find_order(*order_id)
     }
     pub fn pop(&mut self, side: Side) -> Option<OrderBase> {
-        match side {
-            Side::Ask => Some(self.asks.pop()?.1.order),
-            Side::Bid => Some(self.bids.pop()?.1.order),
-        }
+        let (_, order_id) = self.levels(side).next()?.1.order_ids.first_key_value()?;
+        self.delete_order(*order_id)
     }
     pub fn insert_order(&mut self, order: OrderBase) {
-        match order.side {
-            Side::Ask => {
-                self.asks.push(order.id, AskOrder { order });
-            }
-            Side::Bid => {
-                self.bids.push(order.id, BidOrder { order });
-            }
-        }
+        let level = match order.side {
+            Side::Ask => self.asks.entry(order.limit).or_default(),
+            Side::Bid => self.bids.entry(order.limit).or_default(),
+        };
+        // orders put back after a partial fill or an amendment keep their place in the queue
+        let key = (order.sequence, self.insertions);
+        self.insertions += 1;
+        level.order_ids.insert(key, order.id);
+        level.quantity += order.displayed_quantity();
+        self.changed.insert((order.side, order.limit));
+        self.accounts
+            .entry(order.account_id)
+            .or_default()
+            .insert(order.id);
+        self.orders.insert(order.id, (key, order));
     }
     pub fn is_empty(&self, side: Side) -> bool {
         match side {
@@ -49,73 +72,289 @@ impl OrderBook {
         }
     }
     pub fn delete_order(&mut self, order_id: Uuid) -> Option<OrderBase> {
-        if let Some(ask_order) = self.asks.remove(&order_id) {
-            return Some(ask_order.order);
+        let (key, order) = self.orders.remove(&order_id)?;
+        let levels = match order.side {
+            Side::Ask => &mut self.asks,
+            Side::Bid => &mut self.bids,
+        };
+        self.changed.insert((order.side, order.limit));
+        if let btree_map::Entry::Occupied(mut entry) = levels.entry(order.limit) {
+            let level = entry.get_mut();
+            level.order_ids.remove(&key);
+            level.quantity -= order.displayed_quantity();
+            if level.order_ids.is_empty() {
+                entry.remove();
+            }
         }
-        if let Some(bid_order) = self.bids.remove(&order_id) {
-            return Some(bid_order.order);
+        if let hash_map::Entry::Occupied(mut entry) = self.accounts.entry(order.account_id) {
+            entry.get_mut().remove(&order_id);
+            if entry.get().is_empty() {
+                entry.remove();
+            }
         }
-        None
+        Some(order)
     }
 
     pub fn find_order(&self, order_id: Uuid) -> Option<&OrderBase> {
-        if let Some(ask_order) = self.asks.get_priority(&order_id) {
-            return Some(&ask_order.order);
+        self.orders.get(&order_id).map(|(_, order)| order)
+    }
+    /// Walks the opposite side of the book in priority order, taking the resting orders
+    /// that `order` crosses. Returns the quantity that could be taken and its notional value.
+    pub fn sweep(&self, order: &OrderBase) -> (usize, f64) {
+        let mut filled = 0;
+        let mut notional = 0.;
+        for counter in self.iter_by_priority(-order.side) {
+            if filled == order.quantity || !order.crosses(counter) {
+                break;
+            }
+            let taken = min(order.quantity - filled, counter.quantity);
+            filled += taken;
+            notional += counter.limit.into_inner() * taken as f64;
         }
-        if let Some(bid_order) = self.bids.get_priority(&order_id) {
-            return Some(&bid_order.order);
+        (filled, notional)
+    }
+    /// Aggregates the displayed quantity and order count at each of the best `levels`
+    /// prices on `side`, best price first.
+    pub fn depth(&self, side: Side, levels: Option<usize>) -> Vec<PriceLevel> {
+        self.levels(side)
+            .take(levels.unwrap_or(usize::MAX))
+            .map(|(price, level)| PriceLevel {
+                price: price.into_inner(),
+                quantity: level.quantity,
+                order_count: level.order_ids.len(),
+            })
+            .collect()
+    }
+    /// The current state of every price level inserted into or deleted from since the last
+    /// call, asks then bids by price. A level that has emptied has no quantity or orders.
+    pub fn take_changed_levels(&mut self) -> Vec<(Side, PriceLevel)> {
+        let mut changed: Vec<(Side, NotNan<f64>)> = self.changed.drain().collect();
+        changed.sort_by_key(|&(side, price)| (side as i32, price));
+        changed
+            .into_iter()
+            .map(|(side, price)| {
+                let level = match side {
+                    Side::Ask => self.asks.get(&price),
+                    Side::Bid => self.bids.get(&price),
+                };
+                let level = PriceLevel {
+                    price: price.into_inner(),
+                    quantity: level.map_or(0, |level| level.quantity),
+                    order_count: level.map_or(0, |level| level.order_ids.len()),
+                };
+                (side, level)
+            })
+            .collect()
+    }
+    /// Every resting order on `side` in priority order. `account_id` and hidden iceberg
+    /// quantity are only shown on orders owned by `viewer`.
+    pub fn snapshot(&self, side: Side, viewer: Option<AccountId>) -> Vec<BookEntryView> {
+        self.levels(side)
+            .flat_map(|(_, level)| level.order_ids.values().enumerate())
+            .map(|(queue_position, order_id)| {
+                let (_, order) = &self.orders[order_id];
+                let owned = viewer == Some(order.account_id);
+                BookEntryView {
+                    id: order.id.to_string(),
+                    account_id: owned.then(|| order.account_id.as_uuid().to_string()),
+                    side: order.side,
+                    price: order.limit.into_inner(),
+                    quantity: if owned {
+                        order.quantity
+                    } else {
+                        order.displayed_quantity()
+                    },
+                    timestamp: order.timestamp.into_inner(),
+                    queue_position,
+                }
+            })
+            .collect()
+    }
+    /// Resting orders on `side`, best first
+    pub fn iter_by_priority(&self, side: Side) -> impl Iterator<Item = &OrderBase> {
+        self.levels(side)
+            .flat_map(|(_, level)| level.order_ids.values())
+            .map(|order_id| &self.orders[order_id].1)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &OrderBase> {
+        self.orders.values().map(|(_, order)| order)
+    }
+    pub fn filter_order_by_account(
+        &self,
+        account_id: AccountId,
+    ) -> impl Iterator<Item = &OrderBase> {
+        self.accounts
+            .get(&account_id)
+            .into_iter()
+            .flatten()
+            .map(|order_id| &self.orders[order_id].1)
+    }
+    /// Price levels on `side`, best price first
+    fn levels(&self, side: Side) -> Box<dyn Iterator<Item = (&NotNan<f64>, &Level)> + '_> {
+        match side {
+            Side::Ask => Box::new(self.asks.iter()),
+            Side::Bid => Box::new(self.bids.iter().rev()),
         }
-        None
+    }
+}
+
+/// Stop orders waiting for the last trade price to reach their stop price
+#[derive(Debug, Default)]
+pub struct TriggerBook {
+    // kept in arrival order
+    orders: Vec<OrderBase>,
+}
+
+impl TriggerBook {
+    pub fn insert_order(&mut self, order: OrderBase) {
+        // an amended order goes back to where it arrived
+        let index = self
+            .orders
+            .partition_point(|x| x.sequence <= order.sequence);
+        self.orders.insert(index, order);
+    }
+    /// Removes the next order triggered by `last_price`.
+    /// Stops nearest the market are released first, then by arrival.
+    pub fn pop_triggered(&mut self, last_price: NotNan<f64>) -> Option<OrderBase> {
+        let (index, _) = self
+            .orders
+            .iter()
+            .enumerate()
+            .filter(|(_, order)| order.is_triggered(last_price))
+            .min_by_key(|(_, order)| {
+                order
+                    .stop_price
+                    .map(|stop| stop * f64::from(order.side as i32))
+            })?;
+        Some(self.orders.remove(index))
+    }
+    pub fn delete_order(&mut self, order_id: Uuid) -> Option<OrderBase> {
+        let index = self.orders.iter().position(|x| x.id == order_id)?;
+        Some(self.orders.remove(index))
+    }
+    pub fn find_order(&self, order_id: Uuid) -> Option<&OrderBase> {
+        self.orders.iter().find(|&x| x.id == order_id)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &OrderBase> {
+        self.orders.iter()
     }
     pub fn filter_order_by_account(
         &self,
         account_id: AccountId,
     ) -> impl Iterator<Item = &OrderBase> {
-        self.bids
+        self.orders
             .iter()
-            .map(|x| &x.1.order)
-            .filter(move |x| x.account_id == account_id)
-            .chain(
-                self.asks
-                    .iter()
-                    .map(|x| &x.1.order)
-                    .filter(move |x| x.account_id == account_id),
-            )
+            .filter(move |&x| x.account_id == account_id)
     }
 }
 
-#[derive(Debug)]
-pub struct ProcessedOrders {
-    orders: VecDeque<OrderBase>,
-    capacity: usize,
+/// How long completed orders are kept in the order history
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Retention {
+    /// Most completed orders kept, oldest are dropped first
+    pub max_orders: Option<usize>,
+    /// Seconds a completed order is kept after its last update
+    pub max_age: Option<f64>,
 }
 
-impl Default for ProcessedOrders {
-    fn default() -> ProcessedOrders {
-        ProcessedOrders {
-            orders: VecDeque::with_capacity(64),
-            capacity: 64,
-        }
-    }
+/// Orders that have executed, been cancelled or expired, indexed by id and by account
+#[derive(Debug, Default)]
+pub struct OrderHistory {
+    orders: HashMap<Uuid, OrderBase>,
+    // order ids in the order they completed, for retention
+    completed: VecDeque<Uuid>,
+    // order ids of each account by arrival
+    accounts: HashMap<AccountId, BTreeMap<u64, Uuid>>,
+    retention: Retention,
 }
 
-impl ProcessedOrders {
+impl OrderHistory {
+    pub fn with_retention(retention: Retention) -> OrderHistory {
+        OrderHistory {
+            retention,
+            ..Default::default()
+        }
+    }
     pub fn push(&mut self, order: OrderBase) {
-        if self.orders.len() >= self.capacity {
-            self.orders.pop_front();
+        let now = order.updated_at.into_inner();
+        self.completed.push_back(order.id);
+        self.accounts
+            .entry(order.account_id)
+            .or_default()
+            .insert(order.arrival, order.id);
+        self.orders.insert(order.id, order);
+        self.prune(now);
+    }
+    /// Drops orders beyond the retention limits as of `now`
+    pub fn prune(&mut self, now: f64) {
+        while let Some(order_id) = self.completed.front() {
+            let order = &self.orders[order_id];
+            let too_many = self
+                .retention
+                .max_orders
+                .is_some_and(|max_orders| self.completed.len() > max_orders);
+            let too_old = self
+                .retention
+                .max_age
+                .is_some_and(|max_age| order.updated_at.into_inner() < now - max_age);
+            if !too_many && !too_old {
+                break;
+            }
+            let order = self
+                .orders
+                .remove(order_id)
+                .expect("completed orders are stored");
+            self.completed.pop_front();
+            if let hash_map::Entry::Occupied(mut entry) = self.accounts.entry(order.account_id) {
+                entry.get_mut().remove(&order.arrival);
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
         }
-        self.orders.push_back(order);
     }
     pub fn find_order(&self, order_id: Uuid) -> Option<&OrderBase> {
-        self.orders.iter().find(|&x| x.id == order_id)
+        self.orders.get(&order_id)
     }
+    /// Orders of `account_id` that arrived after `cursor`, oldest first
     pub fn filter_order_by_account(
         &self,
         account_id: AccountId,
+        cursor: Option<u64>,
     ) -> impl Iterator<Item = &OrderBase> {
-        self.orders
-            .iter()
-            .filter(move |&x| x.account_id == account_id)
+        let after = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+        self.accounts
+            .get(&account_id)
+            .into_iter()
+            .flat_map(move |order_ids| order_ids.range((after, Bound::Unbounded)))
+            .map(|(_, order_id)| &self.orders[order_id])
+    }
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+}
+
+/// Optional criteria an order must meet, as used by order history queries
+#[derive(Debug, Copy, Clone, Default)]
+pub struct OrderFilter {
+    pub status: Option<Status>,
+    pub side: Option<Side>,
+    /// Earliest creation time, inclusive
+    pub from: Option<f64>,
+    /// Latest creation time, exclusive
+    pub to: Option<f64>,
+}
+
+impl OrderFilter {
+    pub fn matches(&self, order: &OrderBase) -> bool {
+        let timestamp = order.timestamp.into_inner();
+        self.status.is_none_or(|status| order.status == status)
+            && self.side.is_none_or(|side| order.side == side)
+            && self.from.is_none_or(|from| timestamp >= from)
+            && self.to.is_none_or(|to| timestamp < to)
     }
 }
 
@@ -136,23 +375,76 @@ impl Neg for Side {
     }
 }
 
+#[derive(Debug, Copy, Clone, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum OrderType {
+    #[default]
+    Limit,
+    Market,
+}
+
+/// The smallest price increment
+pub const TICK_SIZE: f64 = 0.01;
+
+/// What to do with a post only order that would take liquidity on arrival
+#[derive(Debug, Copy, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum PostOnly {
+    Reject,
+    /// Move the limit to one tick behind the best opposing order
+    Reprice,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum TimeInForce {
+    #[default]
+    GoodTillCancelled,
+    ImmediateOrCancel,
+    FillOrKill,
+    /// Expires at the given unix timestamp in seconds
+    GoodTillDate(f64),
+}
+
 #[derive(Debug, Copy, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum Status {
     Created,
     Pending,
     Executed,
     Cancelled,
+    Expired,
+    /// A stop order whose stop price has been reached
+    Triggered,
+    /// Some but not all of the order has traded and the rest is still live
+    PartiallyFilled,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct OrderBase {
     pub limit: NotNan<f64>,
+    // wall clock time the order was built, kept as metadata only
     timestamp: NotNan<f64>,
+    // assigned by the market on arrival, orders at the same price queue in sequence order
+    sequence: u64,
+    // the sequence number the order arrived with, which unlike `sequence` never changes
+    arrival: u64,
     pub quantity: usize,
     pub side: Side,
     pub account_id: AccountId,
     id: Uuid,
     pub status: Status,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    pub post_only: Option<PostOnly>,
+    pub stop_price: Option<NotNan<f64>>,
+    /// The most an iceberg order shows in the book at once
+    pub peak: Option<usize>,
+    // what is left of the iceberg's current slice
+    displayed: usize,
+    // the quantity the order was placed with, which an amendment leaves alone
+    original_quantity: usize,
+    filled_quantity: usize,
+    // wall clock time of the last fill, amendment or status change
+    updated_at: NotNan<f64>,
+    // every trade this order took part in, oldest first
+    fills: Vec<Fill>,
 }
 
 // Make this a builder instead of a new
@@ -167,25 +459,226 @@ impl OrderBase {
         Ok(OrderBase {
             limit: NotNan::new(limit)?,
             timestamp: NotNan::new(timestamp)?,
+            sequence: 0,
+            arrival: 0,
             quantity,
             side,
             account_id,
             id: Uuid::new_v4(),
             status: Status::Created,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: None,
+            stop_price: None,
+            peak: None,
+            displayed: quantity,
+            original_quantity: quantity,
+            filled_quantity: 0,
+            updated_at: NotNan::new(timestamp)?,
+            fills: Vec::new(),
         })
     }
+    /// A market order is given an infinitely aggressive limit so that it crosses
+    /// every resting order on the opposite side. It never rests in the book.
+    pub fn build_market(
+        quantity: usize,
+        side: Side,
+        account_id: AccountId,
+    ) -> Result<OrderBase, Box<dyn Error>> {
+        let mut order = OrderBase::build(
+            f64::INFINITY * f64::from(side as i32),
+            quantity,
+            side,
+            account_id,
+        )?;
+        order.order_type = OrderType::Market;
+        Ok(order)
+    }
+    pub fn with_time_in_force(mut self, time_in_force: TimeInForce) -> OrderBase {
+        self.time_in_force = time_in_force;
+        self
+    }
+    pub fn with_post_only(mut self, post_only: PostOnly) -> OrderBase {
+        self.post_only = Some(post_only);
+        self
+    }
+    /// Holds the order back until the last trade price reaches `stop_price`.
+    /// A limit order becomes a stop-limit order and a market order a stop-market order.
+    pub fn with_stop_price(mut self, stop_price: f64) -> Result<OrderBase, Box<dyn Error>> {
+        self.stop_price = Some(NotNan::new(stop_price)?);
+        Ok(self)
+    }
+    /// Makes this an iceberg order showing at most `peak` of its quantity at a time
+    pub fn with_peak(mut self, peak: usize) -> Result<OrderBase, Box<dyn Error>> {
+        if peak == 0 {
+            return Err("Iceberg peak must be positive".into());
+        }
+        self.peak = Some(peak);
+        self.displayed = min(peak, self.quantity);
+        Ok(self)
+    }
+    /// The quantity visible to the rest of the market
+    pub fn displayed_quantity(&self) -> usize {
+        match self.peak {
+            Some(_) => self.displayed,
+            None => self.quantity,
+        }
+    }
+    /// Records this order's side of `trade`
+    pub fn fill(&mut self, trade: &Trade) {
+        self.quantity -= trade.quantity;
+        self.filled_quantity += trade.quantity;
+        self.displayed = self.displayed.saturating_sub(trade.quantity);
+        let (counter_order_id, fee) = if trade.aggressor_order_id == self.id {
+            (trade.passive_order_id, trade.aggressor_fee)
+        } else {
+            (trade.aggressor_order_id, trade.passive_fee)
+        };
+        self.fills.push(Fill {
+            trade_id: trade.get_id(),
+            price: trade.price,
+            quantity: trade.quantity,
+            counter_order_id,
+            timestamp: trade.timestamp,
+            fee,
+        });
+        if self.quantity == 0 {
+            self.set_status(Status::Executed);
+        } else {
+            self.set_status(Status::PartiallyFilled);
+        }
+    }
+    pub fn fills(&self) -> &[Fill] {
+        &self.fills
+    }
+    pub fn filled_quantity(&self) -> usize {
+        self.filled_quantity
+    }
+    /// The quantity the order was placed with. Amending the order does not change it.
+    pub fn original_quantity(&self) -> usize {
+        self.original_quantity
+    }
+    pub fn average_fill_price(&self) -> Option<f64> {
+        let filled = self.filled_quantity;
+        let notional: f64 = self
+            .fills
+            .iter()
+            .map(|fill| fill.price.into_inner() * fill.quantity as f64)
+            .sum();
+        (filled > 0).then(|| notional / filled as f64)
+    }
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+        self.touch();
+    }
+    /// Marks the order as updated now
+    pub fn touch(&mut self) {
+        if let Ok(timestamp) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            self.updated_at = NotNan::new(timestamp.as_secs_f64()).unwrap_or(self.updated_at);
+        }
+    }
+    /// Shows up to a full peak of the remaining quantity
+    pub fn reveal(&mut self) {
+        self.displayed = min(self.peak.unwrap_or(self.quantity), self.quantity);
+    }
+    /// Shows the next slice of an iceberg order from its reserve.
+    /// The new slice joins the back of the queue under `sequence`.
+    pub fn replenish(&mut self, sequence: u64) {
+        self.reveal();
+        self.set_sequence(sequence);
+    }
+    /// Queues the order behind every order with a lower sequence number.
+    /// Giving a resting order a new sequence number makes it lose time priority.
+    pub fn set_sequence(&mut self, sequence: u64) {
+        self.sequence = sequence;
+    }
+    pub fn get_sequence(&self) -> u64 {
+        self.sequence
+    }
+    /// Numbers the order as it reaches the market, which also sets its time priority
+    pub fn set_arrival(&mut self, sequence: u64) {
+        self.arrival = sequence;
+        self.sequence = sequence;
+    }
+    pub fn get_arrival(&self) -> u64 {
+        self.arrival
+    }
+    /// Sets the remaining quantity, trimming an iceberg's displayed slice to fit
+    pub fn set_quantity(&mut self, quantity: usize) {
+        self.quantity = quantity;
+        self.displayed = min(self.displayed, quantity);
+        self.touch();
+    }
+    /// Whether a trade at `last_price` releases this stop order
+    pub fn is_triggered(&self, last_price: NotNan<f64>) -> bool {
+        match (self.stop_price, self.side) {
+            (Some(stop_price), Side::Bid) => last_price >= stop_price,
+            (Some(stop_price), Side::Ask) => last_price <= stop_price,
+            (None, _) => false,
+        }
+    }
+    /// Whether this order would trade against the resting `counter` order
+    pub fn crosses(&self, counter: &OrderBase) -> bool {
+        let sign = f64::from(self.side as i32);
+        counter.limit * sign <= self.limit * sign
+    }
+    /// Whether any unfilled quantity should rest in the book after matching
+    pub fn can_rest(&self) -> bool {
+        self.order_type == OrderType::Limit
+            && matches!(
+                self.time_in_force,
+                TimeInForce::GoodTillCancelled | TimeInForce::GoodTillDate(_)
+            )
+    }
+    pub fn is_expired(&self, now: f64) -> bool {
+        match self.time_in_force {
+            TimeInForce::GoodTillDate(expiry) => expiry <= now,
+            _ => false,
+        }
+    }
     pub fn get_id(&self) -> Uuid {
         self.id
     }
     pub fn view(&self) -> OrderView {
+        let limit = match self.order_type {
+            OrderType::Limit => Some(self.limit.into_inner()),
+            OrderType::Market => None,
+        };
         OrderView {
-            limit: self.limit.into_inner(),
-            timestamp: self.limit.into_inner(),
+            limit,
+            timestamp: self.timestamp.into_inner(),
+            updated_at: self.updated_at.into_inner(),
+            sequence: self.sequence,
+            arrival: self.arrival,
             quantity: self.quantity,
+            original_quantity: self.original_quantity,
+            filled_quantity: self.filled_quantity,
+            remaining_quantity: self.quantity,
+            average_fill_price: self.average_fill_price(),
             side: self.side,
             account_id: self.account_id.as_uuid().to_string(),
             id: self.id.to_string(),
             status: self.status,
+            order_type: self.order_type,
+            time_in_force: self.time_in_force,
+            post_only: self.post_only,
+            stop_price: self.stop_price.map(|stop_price| stop_price.into_inner()),
+            peak: self.peak,
+        }
+    }
+    /// The view shown to other participants, which leaves out the order's account, fills
+    /// and an iceberg's reserve
+    pub fn public_view(&self) -> PublicOrderView {
+        let limit = match self.order_type {
+            OrderType::Limit => Some(self.limit.into_inner()),
+            OrderType::Market => None,
+        };
+        PublicOrderView {
+            id: self.id.to_string(),
+            side: self.side,
+            limit,
+            quantity: self.displayed_quantity(),
+            timestamp: self.timestamp.into_inner(),
         }
     }
 }
@@ -197,73 +690,104 @@ impl PartialEq for OrderBase {
 }
 impl Eq for OrderBase {}
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OrderView {
-    pub limit: f64,
+    pub limit: Option<f64>,
+    /// When the order was created, in unix seconds
     pub timestamp: f64,
+    /// When the order was last filled, amended or changed status, in unix seconds
+    pub updated_at: f64,
+    pub sequence: u64,
+    /// Sequence number on arrival, used as the cursor when paging through orders
+    pub arrival: u64,
     pub quantity: usize,
+    /// The quantity the order was placed with, whatever it has since been amended to
+    pub original_quantity: usize,
+    pub filled_quantity: usize,
+    pub remaining_quantity: usize,
+    pub average_fill_price: Option<f64>,
     pub side: Side,
     pub account_id: String,
     pub id: String,
     pub status: Status,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    pub post_only: Option<PostOnly>,
+    pub stop_price: Option<f64>,
+    pub peak: Option<usize>,
 }
 
-#[derive(Debug)]
-struct AskOrder {
-    order: OrderBase,
+/// One trade from the point of view of one of its orders
+#[derive(Debug, Clone)]
+pub struct Fill {
+    pub trade_id: Uuid,
+    pub price: NotNan<f64>,
+    pub quantity: usize,
+    /// The order on the other side of the trade
+    pub counter_order_id: Uuid,
+    pub timestamp: f64,
+    /// Paid by this order's account, negative for a rebate
+    pub fee: f64,
 }
 
-impl Ord for AskOrder {
-    fn cmp(&self, other: &Self) -> Ordering {
-        if self.order.limit == other.order.limit {
-            return other.order.timestamp.cmp(&self.order.timestamp);
+impl Fill {
+    pub fn view(&self) -> FillView {
+        FillView {
+            trade_id: self.trade_id.to_string(),
+            price: self.price.into_inner(),
+            quantity: self.quantity,
+            counter_order_id: self.counter_order_id.to_string(),
+            timestamp: self.timestamp,
+            fee: self.fee,
         }
-        other.order.limit.cmp(&self.order.limit)
-    }
-}
-
-impl PartialOrd for AskOrder {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
     }
 }
 
-impl PartialEq for AskOrder {
-    fn eq(&self, other: &Self) -> bool {
-        (self.order.limit == other.order.limit) && (self.order.timestamp == other.order.timestamp)
-    }
-}
-
-impl Eq for AskOrder {}
-
-#[derive(Debug)]
-struct BidOrder {
-    order: OrderBase,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FillView {
+    pub trade_id: String,
+    pub price: f64,
+    pub quantity: usize,
+    pub counter_order_id: String,
+    pub timestamp: f64,
+    pub fee: f64,
 }
 
-impl Ord for BidOrder {
-    fn cmp(&self, other: &Self) -> Ordering {
-        if self.order.limit == other.order.limit {
-            return other.order.timestamp.cmp(&self.order.timestamp);
-        }
-        self.order.limit.cmp(&other.order.limit)
-    }
+/// Resting quantity at a single price on one side of the book
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceLevel {
+    pub price: f64,
+    pub quantity: usize,
+    pub order_count: usize,
 }
 
-impl PartialOrd for BidOrder {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+/// An order as shown to other participants, without its account, fills or hidden quantity
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PublicOrderView {
+    pub id: String,
+    pub side: Side,
+    pub limit: Option<f64>,
+    /// Only the displayed slice of an iceberg order
+    pub quantity: usize,
+    /// When the order was created, in unix seconds
+    pub timestamp: f64,
 }
 
-impl PartialEq for BidOrder {
-    fn eq(&self, other: &Self) -> bool {
-        (self.order.limit == other.order.limit) && (self.order.timestamp == other.order.timestamp)
-    }
+/// A resting order as seen in a full book snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookEntryView {
+    pub id: String,
+    /// Only present on the viewer's own orders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>,
+    pub side: Side,
+    pub price: f64,
+    pub quantity: usize,
+    pub timestamp: f64,
+    /// Number of orders ahead of this one at the same price
+    pub queue_position: usize,
 }
 
-impl Eq for BidOrder {}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -275,86 +799,157 @@ mod tests {
         let mut accounts = account::Accounts::default();
         let account_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 0);
 
-        let ask1 = AskOrder {
-            order: OrderBase {
-                limit: NotNan::new(2.).unwrap(),
-                timestamp: NotNan::new(3.).unwrap(),
-                quantity: 12,
-                side: Side::Ask,
-                account_id: account_id,
-                id: Uuid::new_v4(),
-                status: Status::Created,
-            },
+        let ask1 = OrderBase {
+            limit: NotNan::new(2.).unwrap(),
+            timestamp: NotNan::new(3.).unwrap(),
+            updated_at: NotNan::new(3.).unwrap(),
+            sequence: 3,
+            arrival: 3,
+            quantity: 12,
+            side: Side::Ask,
+            account_id,
+            id: Uuid::new_v4(),
+            status: Status::Created,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: None,
+            stop_price: None,
+            peak: None,
+            displayed: 12,
+            original_quantity: 12,
+            filled_quantity: 0,
+            fills: Vec::new(),
         };
-        let ask2 = AskOrder {
-            order: OrderBase {
-                limit: NotNan::new(0.).unwrap(),
-                timestamp: NotNan::new(3.).unwrap(),
-                quantity: 10,
-                side: Side::Ask,
-                account_id: account_id,
-                id: Uuid::new_v4(),
-                status: Status::Created,
-            },
+        let ask2 = OrderBase {
+            limit: NotNan::new(0.).unwrap(),
+            timestamp: NotNan::new(3.).unwrap(),
+            updated_at: NotNan::new(3.).unwrap(),
+            sequence: 3,
+            arrival: 3,
+            quantity: 10,
+            side: Side::Ask,
+            account_id,
+            id: Uuid::new_v4(),
+            status: Status::Created,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: None,
+            stop_price: None,
+            peak: None,
+            displayed: 10,
+            original_quantity: 10,
+            filled_quantity: 0,
+            fills: Vec::new(),
         };
+        let (ask1_id, ask2_id) = (ask1.id, ask2.id);
+        let mut order_book = OrderBook::default();
+        order_book.insert_order(ask1);
+        order_book.insert_order(ask2);
         // ask2 should be higher priority than ask1 because it has a lower limit price
-        assert!(ask2 > ask1);
+        assert_eq!(order_book.pop(Side::Ask).unwrap().id, ask2_id);
+        assert_eq!(order_book.pop(Side::Ask).unwrap().id, ask1_id);
     }
     #[test]
     fn bid_ordering() {
         let mut accounts = account::Accounts::default();
         let account_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 0);
 
-        let bid1 = BidOrder {
-            order: OrderBase {
-                limit: NotNan::new(2.).unwrap(),
-                timestamp: NotNan::new(3.).unwrap(),
-                quantity: 2,
-                side: Side::Bid,
-                account_id: account_id,
-                id: Uuid::new_v4(),
-                status: Status::Created,
-            },
+        let bid1 = OrderBase {
+            limit: NotNan::new(2.).unwrap(),
+            timestamp: NotNan::new(3.).unwrap(),
+            updated_at: NotNan::new(3.).unwrap(),
+            sequence: 3,
+            arrival: 3,
+            quantity: 2,
+            side: Side::Bid,
+            account_id,
+            id: Uuid::new_v4(),
+            status: Status::Created,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: None,
+            stop_price: None,
+            peak: None,
+            displayed: 2,
+            original_quantity: 2,
+            filled_quantity: 0,
+            fills: Vec::new(),
         };
-        let bid2 = BidOrder {
-            order: OrderBase {
-                limit: NotNan::new(2.).unwrap(),
-                timestamp: NotNan::new(4.).unwrap(),
-                quantity: 3,
-                side: Side::Bid,
-                account_id: account_id,
-                id: Uuid::new_v4(),
-                status: Status::Created,
-            },
+        let bid2 = OrderBase {
+            limit: NotNan::new(2.).unwrap(),
+            timestamp: NotNan::new(4.).unwrap(),
+            updated_at: NotNan::new(4.).unwrap(),
+            sequence: 4,
+            arrival: 4,
+            quantity: 3,
+            side: Side::Bid,
+            account_id,
+            id: Uuid::new_v4(),
+            status: Status::Created,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: None,
+            stop_price: None,
+            peak: None,
+            displayed: 3,
+            original_quantity: 3,
+            filled_quantity: 0,
+            fills: Vec::new(),
         };
-        let bid3 = BidOrder {
-            order: OrderBase {
-                limit: NotNan::new(3.).unwrap(),
-                timestamp: NotNan::new(3.).unwrap(),
-                quantity: 2,
-                side: Side::Bid,
-                account_id: account_id,
-                id: Uuid::new_v4(),
-                status: Status::Created,
-            },
+        let bid3 = OrderBase {
+            limit: NotNan::new(3.).unwrap(),
+            timestamp: NotNan::new(3.).unwrap(),
+            updated_at: NotNan::new(3.).unwrap(),
+            sequence: 3,
+            arrival: 3,
+            quantity: 2,
+            side: Side::Bid,
+            account_id,
+            id: Uuid::new_v4(),
+            status: Status::Created,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: None,
+            stop_price: None,
+            peak: None,
+            displayed: 2,
+            original_quantity: 2,
+            filled_quantity: 0,
+            fills: Vec::new(),
         };
-        let bid4 = BidOrder {
-            order: OrderBase {
-                limit: NotNan::new(2.).unwrap(),
-                timestamp: NotNan::new(4.).unwrap(),
-                quantity: 3,
-                side: Side::Bid,
-                account_id: account_id,
-                id: Uuid::new_v4(),
-                status: Status::Created,
-            },
+        let bid4 = OrderBase {
+            limit: NotNan::new(2.).unwrap(),
+            timestamp: NotNan::new(4.).unwrap(),
+            updated_at: NotNan::new(4.).unwrap(),
+            sequence: 4,
+            arrival: 4,
+            quantity: 3,
+            side: Side::Bid,
+            account_id,
+            id: Uuid::new_v4(),
+            status: Status::Created,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: None,
+            stop_price: None,
+            peak: None,
+            displayed: 3,
+            original_quantity: 3,
+            filled_quantity: 0,
+            fills: Vec::new(),
         };
+        let ids = [bid1.id, bid2.id, bid3.id, bid4.id];
+        let mut order_book = OrderBook::default();
+        for bid in [bid2, bid4, bid1, bid3] {
+            order_book.insert_order(bid);
+        }
+        // bid3 offers a higher price than the others
+        assert_eq!(order_book.pop(Side::Bid).unwrap().id, ids[2]);
         // bid1 has same limit price as bid2 but bid1 was submitted earlier
-        assert!(bid1 > bid2);
-        // bid2 and bid4 have the same price-time priority
-        assert!(bid2 == bid4);
-        // bid3 offers a higher price than bid4
-        assert!(bid3 > bid4);
+        assert_eq!(order_book.pop(Side::Bid).unwrap().id, ids[0]);
+        // bid2 and bid4 have the same price-time priority so they leave in arrival order
+        assert_eq!(order_book.pop(Side::Bid).unwrap().id, ids[1]);
+        assert_eq!(order_book.pop(Side::Bid).unwrap().id, ids[3]);
     }
     #[test]
     fn order_base_builder() {
@@ -391,38 +986,86 @@ mod tests {
         let ask1 = OrderBase {
             limit: NotNan::new(20.).unwrap(),
             timestamp: NotNan::new(1703713624.0).unwrap(),
+            updated_at: NotNan::new(1703713624.0).unwrap(),
+            sequence: 1,
+            arrival: 1,
             quantity: 10,
             side: Side::Ask,
-            account_id: account_id,
+            account_id,
             id: Uuid::new_v4(),
             status: Status::Created,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: None,
+            stop_price: None,
+            peak: None,
+            displayed: 10,
+            original_quantity: 10,
+            filled_quantity: 0,
+            fills: Vec::new(),
         };
         let ask2 = OrderBase {
             limit: NotNan::new(30.).unwrap(),
             timestamp: NotNan::new(1703713626.0).unwrap(),
+            updated_at: NotNan::new(1703713626.0).unwrap(),
+            sequence: 2,
+            arrival: 2,
             quantity: 20,
             side: Side::Ask,
-            account_id: account_id,
+            account_id,
             id: Uuid::new_v4(),
             status: Status::Created,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: None,
+            stop_price: None,
+            peak: None,
+            displayed: 20,
+            original_quantity: 20,
+            filled_quantity: 0,
+            fills: Vec::new(),
         };
         let ask3 = OrderBase {
             limit: NotNan::new(15.).unwrap(),
             timestamp: NotNan::new(1703713628.0).unwrap(),
+            updated_at: NotNan::new(1703713628.0).unwrap(),
+            sequence: 3,
+            arrival: 3,
             quantity: 1,
             side: Side::Ask,
-            account_id: account_id,
+            account_id,
             id: Uuid::new_v4(),
             status: Status::Created,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: None,
+            stop_price: None,
+            peak: None,
+            displayed: 1,
+            original_quantity: 1,
+            filled_quantity: 0,
+            fills: Vec::new(),
         };
         let ask4 = OrderBase {
             limit: NotNan::new(20.).unwrap(),
             timestamp: NotNan::new(1703713629.0).unwrap(),
+            updated_at: NotNan::new(1703713629.0).unwrap(),
+            sequence: 4,
+            arrival: 4,
             quantity: 30,
             side: Side::Ask,
-            account_id: account_id,
+            account_id,
             id: Uuid::new_v4(),
             status: Status::Created,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            post_only: None,
+            stop_price: None,
+            peak: None,
+            displayed: 30,
+            original_quantity: 30,
+            filled_quantity: 0,
+            fills: Vec::new(),
         };
 
         let (ask1_id, ask2_id, ask3_id, ask4_id) = (ask1.id, ask2.id, ask3.id, ask4.id);
@@ -439,4 +1082,123 @@ mod tests {
         assert_eq!(order_book.pop(Side::Ask).unwrap().id, ask4_id);
         assert_eq!(order_book.pop(Side::Ask).unwrap().id, ask2_id);
     }
+    #[test]
+    fn order_book_sweep() {
+        let mut accounts = account::Accounts::default();
+        let account_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        let mut order_book = OrderBook::default();
+        let ask = OrderBase::build_market(20, Side::Ask, account_id).unwrap();
+        assert_eq!(order_book.sweep(&ask), (0, 0.));
+
+        order_book.insert_order(OrderBase::build(10., 5, Side::Bid, account_id).unwrap());
+        order_book.insert_order(OrderBase::build(12., 5, Side::Bid, account_id).unwrap());
+        order_book.insert_order(OrderBase::build(8., 5, Side::Bid, account_id).unwrap());
+
+        // the best bids are taken first
+        let ask = OrderBase::build_market(7, Side::Ask, account_id).unwrap();
+        assert_eq!(order_book.sweep(&ask), (7, 5. * 12. + 2. * 10.));
+        let ask = OrderBase::build_market(20, Side::Ask, account_id).unwrap();
+        assert_eq!(order_book.sweep(&ask), (15, 5. * 12. + 5. * 10. + 5. * 8.));
+        // a limit order stops at its limit
+        let ask = OrderBase::build(10., 20, Side::Ask, account_id).unwrap();
+        assert_eq!(order_book.sweep(&ask), (10, 5. * 12. + 5. * 10.));
+    }
+    #[test]
+    fn order_book_depth() {
+        let mut accounts = account::Accounts::default();
+        let account_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        let mut order_book = OrderBook::default();
+        assert_eq!(order_book.depth(Side::Bid, None), vec![]);
+
+        order_book.insert_order(OrderBase::build(10., 5, Side::Bid, account_id).unwrap());
+        order_book.insert_order(OrderBase::build(12., 3, Side::Bid, account_id).unwrap());
+        order_book.insert_order(OrderBase::build(10., 2, Side::Bid, account_id).unwrap());
+        order_book.insert_order(OrderBase::build(8., 1, Side::Bid, account_id).unwrap());
+        order_book.insert_order(
+            OrderBase::build(13., 100, Side::Ask, account_id)
+                .unwrap()
+                .with_peak(10)
+                .unwrap(),
+        );
+        order_book.insert_order(OrderBase::build(14., 4, Side::Ask, account_id).unwrap());
+
+        let level = |price, quantity, order_count| PriceLevel {
+            price,
+            quantity,
+            order_count,
+        };
+        assert_eq!(
+            order_book.depth(Side::Bid, None),
+            vec![level(12., 3, 1), level(10., 7, 2), level(8., 1, 1)]
+        );
+        assert_eq!(
+            order_book.depth(Side::Bid, Some(2)),
+            vec![level(12., 3, 1), level(10., 7, 2)]
+        );
+        assert_eq!(order_book.depth(Side::Bid, Some(0)), vec![]);
+        // only the peak of an iceberg is shown
+        assert_eq!(
+            order_book.depth(Side::Ask, None),
+            vec![level(13., 10, 1), level(14., 4, 1)]
+        );
+    }
+    #[test]
+    fn order_book_snapshot() {
+        let mut accounts = account::Accounts::default();
+        let alice_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 0);
+        let bob_id = accounts.create_new_account(NotNan::new(1e5).unwrap(), 0);
+
+        let mut order_book = OrderBook::default();
+        let bid_1 = OrderBase::build(10., 5, Side::Bid, alice_id).unwrap();
+        let bid_2 = OrderBase::build(12., 3, Side::Bid, bob_id).unwrap();
+        let bid_3 = OrderBase::build(10., 20, Side::Bid, bob_id)
+            .unwrap()
+            .with_peak(2)
+            .unwrap();
+        let ids = [bid_2.get_id(), bid_1.get_id(), bid_3.get_id()].map(|id| id.to_string());
+        order_book.insert_order(bid_1);
+        order_book.insert_order(bid_2);
+        order_book.insert_order(bid_3);
+
+        let snapshot = order_book.snapshot(Side::Bid, Some(alice_id));
+        let summary: Vec<_> = snapshot
+            .iter()
+            .map(|entry| {
+                (
+                    entry.id.clone(),
+                    entry.price,
+                    entry.quantity,
+                    entry.queue_position,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (ids[0].clone(), 12., 3, 0),
+                (ids[1].clone(), 10., 5, 0),
+                // the hidden part of Bob's iceberg is not shown to Alice
+                (ids[2].clone(), 10., 2, 1),
+            ]
+        );
+        // only Alice's own order shows its account
+        let owners: Vec<_> = snapshot
+            .iter()
+            .map(|entry| entry.account_id.clone())
+            .collect();
+        assert_eq!(
+            owners,
+            vec![None, Some(alice_id.as_uuid().to_string()), None]
+        );
+
+        let snapshot = order_book.snapshot(Side::Bid, Some(bob_id));
+        assert_eq!(snapshot[2].quantity, 20);
+        assert!(order_book
+            .snapshot(Side::Bid, None)
+            .iter()
+            .all(|entry| entry.account_id.is_none()));
+        assert_eq!(order_book.snapshot(Side::Ask, None), vec![]);
+    }
 }
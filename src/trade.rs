@@ -0,0 +1,163 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ordered_float::NotNan;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::account::AccountId;
+use crate::order::{OrderBase, Side};
+
+/// A single execution between an incoming (aggressor) order and a resting (passive) order.
+#[derive(Debug, Clone)]
+pub struct Trade {
+    id: Uuid,
+    pub price: NotNan<f64>,
+    pub quantity: usize,
+    pub aggressor_side: Side,
+    pub aggressor_order_id: Uuid,
+    pub passive_order_id: Uuid,
+    pub aggressor_account_id: AccountId,
+    pub passive_account_id: AccountId,
+    pub timestamp: f64,
+    /// Charged to each side when the trade is settled, negative for a rebate
+    pub aggressor_fee: f64,
+    pub passive_fee: f64,
+}
+
+impl Trade {
+    pub fn new(
+        aggressor: &OrderBase,
+        passive: &OrderBase,
+        price: NotNan<f64>,
+        quantity: usize,
+    ) -> Trade {
+        // A clock set before the epoch is not worth failing a match over
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs_f64())
+            .unwrap_or_default();
+        Trade {
+            id: Uuid::new_v4(),
+            price,
+            quantity,
+            aggressor_side: aggressor.side,
+            aggressor_order_id: aggressor.get_id(),
+            passive_order_id: passive.get_id(),
+            aggressor_account_id: aggressor.account_id,
+            passive_account_id: passive.account_id,
+            timestamp,
+            aggressor_fee: 0.,
+            passive_fee: 0.,
+        }
+    }
+    pub fn get_id(&self) -> Uuid {
+        self.id
+    }
+    pub fn view(&self) -> TradeView {
+        TradeView {
+            id: self.id.to_string(),
+            price: self.price.into_inner(),
+            quantity: self.quantity,
+            aggressor_side: self.aggressor_side,
+            aggressor_order_id: self.aggressor_order_id.to_string(),
+            passive_order_id: self.passive_order_id.to_string(),
+            timestamp: self.timestamp,
+            aggressor_fee: self.aggressor_fee,
+            passive_fee: self.passive_fee,
+        }
+    }
+    /// The view shown on the public trade tape, which leaves out the orders involved
+    pub fn public_view(&self) -> PublicTradeView {
+        PublicTradeView {
+            id: self.id.to_string(),
+            price: self.price.into_inner(),
+            quantity: self.quantity,
+            aggressor_side: self.aggressor_side,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+/// Trades in execution order, indexed by id. Once there are more than `max_trades` the
+/// oldest are dropped.
+#[derive(Debug, Default)]
+pub struct TradeLog {
+    trades: Vec<Trade>,
+    // position of each trade in `trades`, counting those dropped before it
+    index: HashMap<Uuid, usize>,
+    dropped: usize,
+    max_trades: Option<usize>,
+}
+
+impl TradeLog {
+    pub fn with_max_trades(max_trades: Option<usize>) -> TradeLog {
+        TradeLog {
+            max_trades,
+            ..Default::default()
+        }
+    }
+    pub fn push(&mut self, trade: Trade) {
+        self.index
+            .insert(trade.get_id(), self.dropped + self.trades.len());
+        self.trades.push(trade);
+        // dropping in batches of `max_trades` keeps the cost of a push constant on average
+        if let Some(max_trades) = self.max_trades {
+            if self.trades.len() >= 2 * max_trades.max(1) {
+                let excess = self.trades.len() - max_trades;
+                for trade in self.trades.drain(..excess) {
+                    self.index.remove(&trade.get_id());
+                }
+                self.dropped += excess;
+            }
+        }
+    }
+    /// The trades kept, oldest first
+    pub fn as_slice(&self) -> &[Trade] {
+        &self.trades[self.start()..]
+    }
+    /// Up to `limit` trades following the trade `since`, or the latest `limit` trades when
+    /// `since` is not given, oldest first. `None` if `since` is not in the log.
+    pub fn since(&self, since: Option<Uuid>, limit: usize) -> Option<&[Trade]> {
+        let trades = self.as_slice();
+        match since {
+            Some(since) => {
+                let position = self
+                    .index
+                    .get(&since)?
+                    .checked_sub(self.dropped + self.start())?;
+                let end = min(position + 1 + limit, trades.len());
+                Some(&trades[position + 1..end])
+            }
+            None => Some(&trades[trades.len().saturating_sub(limit)..]),
+        }
+    }
+    // trades past `max_trades` waiting to be dropped are hidden
+    fn start(&self) -> usize {
+        self.max_trades
+            .map_or(0, |max_trades| self.trades.len().saturating_sub(max_trades))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TradeView {
+    pub id: String,
+    pub price: f64,
+    pub quantity: usize,
+    pub aggressor_side: Side,
+    pub aggressor_order_id: String,
+    pub passive_order_id: String,
+    pub timestamp: f64,
+    pub aggressor_fee: f64,
+    pub passive_fee: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PublicTradeView {
+    pub id: String,
+    pub price: f64,
+    pub quantity: usize,
+    pub aggressor_side: Side,
+    pub timestamp: f64,
+}
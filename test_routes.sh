@@ -10,7 +10,7 @@ echo $ACCOUNT_ID_1
 echo "[POST] /account/new"
 ACCOUNT_ID_2=$(curl -s \
     -H 'Content-Type: application/json' \
-    -d '{ "account_balance": 2000.0, "position": 0 }' \
+    -d '{ "account_balance": 2000.0, "position": 0, "max_leverage": 2.0 }' \
     -X POST \
     "http://localhost:3000/api/account/new")
 echo $ACCOUNT_ID_2
@@ -109,6 +109,16 @@ ORDER_1_1=$(curl -s \
 
 echo $ORDER_1_1
 
+ORDER_1_1=$(echo $ORDER_1_1 | grep -Eo '"id":".*?"' | grep -o '[a-z0-9\-]*' | tail -n1) 
+
+echo "[PATCH] /order/:id"
+curl \
+    -H 'Content-Type: application/json' \
+    -H "account-id: ${ACCOUNT_ID_1}" \
+    -d '{ "quantity": 5 }' \
+    -X PATCH \
+    "http://localhost:3000/api/order/${ORDER_1_1}" && echo
+
 echo "[POST] /api/order/new"
 ORDER_1_2=$(curl -s \
     -H 'Content-Type: application/json' \
@@ -119,7 +129,55 @@ ORDER_1_2=$(curl -s \
 
 echo $ORDER_1_2
 
+ORDER_1_2=$(echo $ORDER_1_2 | grep -Eo '"id":".*?"' | grep -o '[a-z0-9\-]*' | tail -n1) 
+
+echo "[POST] /api/order/new"
+curl \
+    -H 'Content-Type: application/json' \
+    -H "account-id: ${ACCOUNT_ID_2}" \
+    -d '{ "quantity": 2, "side": "Bid", "order_type": "Market" }' \
+    -X POST \
+    "http://localhost:3000/api/order/new" && echo
+
 echo "[GET] /market/quote"
 curl \
     -X GET \
     "http://localhost:3000/api/market/quote"
+
+echo "[GET] /market/depth"
+curl \
+    -X GET \
+    "http://localhost:3000/api/market/depth?levels=5"
+
+echo "[GET] /market/book"
+curl \
+    -H "account-id: ${ACCOUNT_ID_1}" \
+    -X GET \
+    "http://localhost:3000/api/market/book"
+
+echo "[GET] /order/:id/fills"
+curl \
+    -H "account-id: ${ACCOUNT_ID_1}" \
+    -X GET \
+    "http://localhost:3000/api/order/${ORDER_1_2}/fills" && echo
+
+echo "[GET] /order/?status=&side=&limit="
+curl \
+    -H "account-id: ${ACCOUNT_ID_1}" \
+    -X GET \
+    "http://localhost:3000/api/order?status=Executed&side=Ask&limit=10" && echo
+
+echo "[GET] /market/trades"
+curl \
+    -X GET \
+    "http://localhost:3000/api/market/trades?limit=10" && echo
+
+echo "[GET] /account/liquidations"
+curl \
+    -H "account-id: ${ACCOUNT_ID_2}" \
+    -X GET \
+    "http://localhost:3000/api/account/liquidations" && echo
+
+# /api/market/stream is a WebSocket, e.g. `websocat ws://localhost:3000/api/market/stream`
+# /api/account/stream is the same for one account, e.g.
+# `websocat -H "account-id: ${ACCOUNT_ID_1}" ws://localhost:3000/api/account/stream`